@@ -0,0 +1,9 @@
+-- what spotti.py and add-id.py left us with. the only one that can already
+-- be there, so the only one with `if not exists`
+create table if not exists songs (
+    name text,
+    album text,
+    artist text,
+    date text unique,
+    id text
+);
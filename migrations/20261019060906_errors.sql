@@ -0,0 +1,9 @@
+create table errors (
+    id integer primary key,
+    at text not null default (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    context text not null,
+    message text not null,
+    details text
+);
+
+create index errors_at on errors (at);
@@ -0,0 +1,8 @@
+-- the owner's tokens, so a restart (or the auth subcommand) doesn't mean
+-- logging in through the browser again. there's only ever one row.
+create table global_auth (
+    id integer primary key check (id = 1),
+    access_token text not null,
+    refresh_token text not null,
+    expires_at text
+);
@@ -0,0 +1,14 @@
+-- listens on their way to somewhere else, like listenbrainz. rows stay after
+-- they're sent, so there's a record of what went where.
+create table outbox (
+    service text not null,
+    date text not null references songs (date),
+    attempts integer not null default 0,
+    -- not before this, after a failure
+    retry_at text,
+    last_error text,
+    submitted_at text,
+    primary key (service, date)
+);
+
+create index outbox_pending on outbox (service, retry_at) where submitted_at is null;
@@ -0,0 +1,29 @@
+-- full-text search over songs, kept up to date by triggers. it only holds
+-- the index, the text stays in songs
+create virtual table songs_search using fts5 (
+    name,
+    album,
+    artist,
+    content = 'songs',
+    content_rowid = 'rowid',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+insert into songs_search (songs_search) values ('rebuild');
+
+create trigger songs_search_insert after insert on songs begin
+    insert into songs_search (rowid, name, album, artist)
+    values (new.rowid, new.name, new.album, new.artist);
+end;
+
+create trigger songs_search_delete after delete on songs begin
+    insert into songs_search (songs_search, rowid, name, album, artist)
+    values ('delete', old.rowid, old.name, old.album, old.artist);
+end;
+
+create trigger songs_search_update after update on songs begin
+    insert into songs_search (songs_search, rowid, name, album, artist)
+    values ('delete', old.rowid, old.name, old.album, old.artist);
+    insert into songs_search (rowid, name, album, artist)
+    values (new.rowid, new.name, new.album, new.artist);
+end;
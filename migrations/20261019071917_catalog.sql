@@ -0,0 +1,30 @@
+-- what spotify told us about the tracks in songs. songs only ever had the
+-- track id, so older listens find their album and artists once the track gets
+-- played again
+create table albums (
+    id text primary key,
+    name text not null
+);
+
+create table artists (
+    id text primary key,
+    name text not null
+);
+
+create table tracks (
+    id text primary key,
+    name text not null,
+    album_id text not null references albums (id)
+);
+
+-- in the order spotify lists them
+create table track_artists (
+    track_id text not null references tracks (id),
+    position integer not null,
+    artist_id text not null references artists (id),
+    primary key (track_id, position)
+);
+
+create index track_artists_artist on track_artists (artist_id);
+create index tracks_album on tracks (album_id);
+create index songs_id on songs (id);
@@ -0,0 +1,21 @@
+-- the rest of what recently-played tells us. all nullable, tracks from before
+-- this fill in the next time they're played
+alter table tracks add column duration_ms integer;
+alter table tracks add column explicit boolean;
+alter table tracks add column popularity integer;
+alter table tracks add column track_number integer;
+alter table tracks add column disc_number integer;
+alter table tracks add column isrc text;
+
+alter table albums add column release_date text;
+alter table albums add column release_date_precision text;
+-- a json array of {url, height, width}, biggest first
+alter table albums add column images text;
+
+-- where a listen was played from: a playlist, album, artist or show
+create table listen_contexts (
+    date text primary key references songs (date),
+    type text not null,
+    uri text not null,
+    href text
+);
@@ -0,0 +1,3 @@
+-- when the service said no in a way trying again won't change, like a 400.
+-- last_error says why
+alter table outbox add column rejected_at text;
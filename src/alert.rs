@@ -0,0 +1,285 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+/// discord won't take more than 2000 characters in a message
+const MAX_MESSAGE_LEN: usize = 1900;
+
+const DEFAULT_DEDUP_SECS: u64 = 60 * 60;
+const DEFAULT_MAX_PER_HOUR: u32 = 12;
+
+/// how a sink is written in the config file, e.g.
+///
+/// ```toml
+/// [[alerts]]
+/// type = "webhook"
+/// url = "https://discord.com/api/webhooks/..."
+/// ```
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// POST some json that both discord and slack understand
+    Webhook { url: String },
+
+    /// write the error to a file and SIGUSR1 whoever is in the pidfile
+    Pidfile {
+        pidfile: PathBuf,
+        error_file: PathBuf,
+    },
+
+    /// run a program with the alert on stdin
+    Command { command: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    Webhook(Url),
+    Pidfile {
+        pidfile: PathBuf,
+        error_file: PathBuf,
+    },
+    Command(Vec<String>),
+    /// DM someone. comes from the `[discord]` section rather than `[[alerts]]`
+    #[cfg(feature = "discord")]
+    Discord {
+        token: String,
+        owner: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertConfig {
+    pub sinks: Vec<Sink>,
+
+    /// identical alerts inside this window are only sent once
+    pub dedup: Duration,
+
+    /// no matter what, don't send more than this many an hour
+    pub max_per_hour: u32,
+}
+
+impl AlertConfig {
+    pub fn new(sinks: Vec<Sink>, dedup_secs: Option<u64>, max_per_hour: Option<u32>) -> Self {
+        AlertConfig {
+            sinks,
+            dedup: Duration::from_secs(dedup_secs.unwrap_or(DEFAULT_DEDUP_SECS)),
+            max_per_hour: max_per_hour.unwrap_or(DEFAULT_MAX_PER_HOUR),
+        }
+    }
+}
+
+impl TryFrom<SinkConfig> for Sink {
+    type Error = url::ParseError;
+
+    fn try_from(config: SinkConfig) -> Result<Self, Self::Error> {
+        Ok(match config {
+            SinkConfig::Webhook { url } => Sink::Webhook(Url::parse(&url)?),
+            SinkConfig::Pidfile {
+                pidfile,
+                error_file,
+            } => Sink::Pidfile {
+                pidfile,
+                error_file,
+            },
+            SinkConfig::Command { command } => Sink::Command(command),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    /// what we were doing, like "recently-played"
+    pub context: String,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl Alert {
+    fn key(&self) -> String {
+        format!("{}\n{}", self.context, self.message)
+    }
+
+    fn text(&self, repeats: u32, dropped: u32) -> String {
+        let mut text = format!("{}: {}", self.context, self.message);
+        if repeats > 0 {
+            text.push_str(&format!(" (and {repeats} more like it)"));
+        }
+        if dropped > 0 {
+            text.push_str(&format!(
+                "\n({dropped} other alerts were dropped, check the logs)"
+            ));
+        }
+        if let Some(details) = &self.details {
+            text.push_str("\n```\n");
+            text.push_str(details);
+            text.push_str("\n```");
+        }
+
+        if text.len() > MAX_MESSAGE_LEN {
+            text.truncate(text.floor_char_boundary(MAX_MESSAGE_LEN));
+            if text.matches("```").count() % 2 == 1 {
+                text.push_str("\n```");
+            }
+            text.push_str("\n(truncated)");
+        }
+
+        text
+    }
+}
+
+#[derive(Default)]
+struct History {
+    /// when we last sent each distinct alert, and how many we've swallowed since
+    seen: HashMap<String, (Instant, u32)>,
+    sent: VecDeque<Instant>,
+    dropped: u32,
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    http: reqwest::Client,
+    history: Mutex<History>,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig, http: reqwest::Client) -> Self {
+        Alerter {
+            config,
+            http,
+            history: Mutex::new(History::default()),
+        }
+    }
+
+    /// send the alert everywhere it's supposed to go, unless we've said the same
+    /// thing recently or have been too noisy. returns whether it went out.
+    pub async fn alert(&self, alert: Alert) -> bool {
+        if self.config.sinks.is_empty() {
+            return false;
+        }
+
+        let Some(text) = self.admit(&alert, Instant::now()) else {
+            return false;
+        };
+
+        for sink in &self.config.sinks {
+            if let Err(err) = self.deliver(sink, &alert, &text).await {
+                tracing::warn!("couldn't send alert to {sink:?}: {err}");
+            }
+        }
+
+        true
+    }
+
+    fn admit(&self, alert: &Alert, now: Instant) -> Option<String> {
+        let mut history = self.history.lock().unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        let dedup = self.config.dedup;
+
+        let key = alert.key();
+        let repeats = match history.seen.get_mut(&key) {
+            Some((at, repeats)) if now - *at < dedup => {
+                *repeats += 1;
+                tracing::debug!("not alerting about {} again", alert.context);
+                return None;
+            }
+            Some((_, repeats)) => *repeats,
+            None => 0,
+        };
+
+        // hang on to the ones we still owe a repeat count for
+        history
+            .seen
+            .retain(|_, (at, repeats)| now - *at < dedup || *repeats > 0);
+
+        while history.sent.front().is_some_and(|at| now - *at >= hour) {
+            history.sent.pop_front();
+        }
+        if history.sent.len() as u32 >= self.config.max_per_hour {
+            history.dropped += 1;
+            tracing::warn!("too many alerts, dropping {}", alert.context);
+            return None;
+        }
+
+        let dropped = std::mem::take(&mut history.dropped);
+
+        history.seen.insert(key, (now, 0));
+        history.sent.push_back(now);
+
+        Some(alert.text(repeats, dropped))
+    }
+
+    async fn deliver(&self, sink: &Sink, alert: &Alert, text: &str) -> Result<(), String> {
+        match sink {
+            Sink::Webhook(url) => {
+                // discord wants content, slack wants text
+                self.http
+                    .post(url.clone())
+                    .json(&serde_json::json!({ "content": text, "text": text }))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|err| err.to_string())?;
+            }
+
+            Sink::Pidfile {
+                pidfile,
+                error_file,
+            } => {
+                tokio::fs::write(error_file, text)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                let pid = tokio::fs::read_to_string(pidfile)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                tokio::process::Command::new("kill")
+                    .arg("-usr1")
+                    .arg(pid.trim())
+                    .output()
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+
+            Sink::Command(command) => {
+                let Some((program, args)) = command.split_first() else {
+                    return Err(String::from("empty command"));
+                };
+
+                let mut child = tokio::process::Command::new(program)
+                    .args(args)
+                    .env("SPOTTI_ALERT_CONTEXT", &alert.context)
+                    .env("SPOTTI_ALERT_MESSAGE", &alert.message)
+                    .stdin(std::process::Stdio::piped())
+                    .stdout(std::process::Stdio::null())
+                    .spawn()
+                    .map_err(|err| err.to_string())?;
+
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin
+                        .write_all(text.as_bytes())
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+
+                let status = child.wait().await.map_err(|err| err.to_string())?;
+                if !status.success() {
+                    return Err(format!("{program} exited with {status}"));
+                }
+            }
+
+            #[cfg(feature = "discord")]
+            Sink::Discord { token, owner } => {
+                crate::discord::direct_message(token, *owner, text)
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! charts as inline svg, so pages don't need any scripts or files to show them
+
+use crate::escape;
+use chrono::{Datelike, NaiveDate};
+use std::collections::HashMap;
+
+const WIDTH: usize = 720;
+const HEIGHT: usize = 120;
+/// room under the bars for the first and last labels
+const LABEL_HEIGHT: usize = 16;
+
+/// one bar per bucket, scaled to the biggest. hovering shows the label and
+/// the count
+pub fn bars(buckets: &[(String, i64)]) -> String {
+    let max = buckets
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let width = WIDTH as f64 / buckets.len().max(1) as f64;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{}\" viewBox=\"0 0 {WIDTH} {}\" font-size=\"11\" font-family=\"sans-serif\">\n",
+        HEIGHT + LABEL_HEIGHT,
+        HEIGHT + LABEL_HEIGHT,
+    );
+    for (i, (label, count)) in buckets.iter().enumerate() {
+        let height = (*count as f64 / max as f64 * HEIGHT as f64).round();
+        svg.push_str(&format!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{height:.1}\" fill=\"#090\"><title>{}: {count}</title></rect>\n",
+            i as f64 * width,
+            HEIGHT as f64 - height,
+            (width - 1.0).max(1.0),
+            escape(label),
+        ));
+    }
+    if let (Some((first, _)), Some((last, _))) = (buckets.first(), buckets.last()) {
+        svg.push_str(&format!(
+            "<text x=\"0\" y=\"{}\">{}</text>\n<text x=\"{WIDTH}\" y=\"{}\" text-anchor=\"end\">{}</text>\n",
+            HEIGHT + LABEL_HEIGHT - 3,
+            escape(first),
+            HEIGHT + LABEL_HEIGHT - 3,
+            escape(last),
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// a bucket for every month from the first day to the last, empty ones too,
+/// labelled like 2024-01
+pub fn by_month(days: impl IntoIterator<Item = NaiveDate>) -> Vec<(String, i64)> {
+    let months = days
+        .into_iter()
+        .map(|day| day.year() * 12 + day.month0() as i32)
+        .collect::<Vec<_>>();
+    let (Some(first), Some(last)) = (months.iter().min(), months.iter().max()) else {
+        return Vec::new();
+    };
+
+    let mut counts = vec![0; (last - first + 1) as usize];
+    for month in &months {
+        counts[(month - first) as usize] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| {
+            let month = first + i as i32;
+            (format!("{}-{:02}", month / 12, month % 12 + 1), count)
+        })
+        .collect()
+}
+
+const CELL: usize = 12;
+const GAP: usize = 2;
+/// from nothing to the most in the year
+const SHADES: [&str; 5] = ["#ebedf0", "#c6e48b", "#7bc96f", "#239a3b", "#196127"];
+
+/// a year as a grid of days, a column a week starting on monday, darker for
+/// more. `label` says what the numbers are
+pub fn calendar(year: i32, days: &HashMap<NaiveDate, i64>, label: &str) -> String {
+    let (Some(start), Some(end)) = (
+        NaiveDate::from_ymd_opt(year, 1, 1),
+        NaiveDate::from_ymd_opt(year, 12, 31),
+    ) else {
+        return String::new();
+    };
+    let max = days
+        .iter()
+        .filter(|(day, _)| day.year() == year)
+        .map(|(_, value)| *value)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let offset = start.weekday().num_days_from_monday() as usize;
+
+    let width = 54 * (CELL + GAP);
+    let height = 7 * (CELL + GAP);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n"
+    );
+    for (i, day) in start.iter_days().take_while(|day| *day <= end).enumerate() {
+        let value = days.get(&day).copied().unwrap_or(0);
+        let shade = if value == 0 {
+            0
+        } else {
+            // anything at all is at least the lightest green
+            1 + ((value - 1) * (SHADES.len() as i64 - 1) / max) as usize
+        };
+        let (column, row) = ((i + offset) / 7, (i + offset) % 7);
+        svg.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{CELL}\" height=\"{CELL}\" fill=\"{}\"><title>{day}: {value} {}</title></rect>\n",
+            column * (CELL + GAP),
+            row * (CELL + GAP),
+            SHADES[shade.min(SHADES.len() - 1)],
+            escape(label),
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
@@ -0,0 +1,738 @@
+//! everything the binary can do, serving being just one of them
+
+use crate::{
+    config::ConfigError,
+    db,
+    error::{self, AppError},
+    export::{self, Bound, ExportError, Format, Range},
+    lastfm::{LastFmClient, LastFmError},
+    outbox,
+    server::{self, AppState, Reloadable},
+    spotify::TRACKS_AT_ONCE,
+    stats::{self, listening_stats},
+    tls::{self, Tls, TlsError},
+    wrapped, Config, SongRecord,
+};
+use axum::{
+    extract::{Query, State},
+    routing, Router,
+};
+use clap::{Parser, Subcommand};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt},
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use url::Url;
+
+/// has to be registered as a redirect uri on the spotify app
+const DEFAULT_AUTH_REDIRECT: &str = "http://127.0.0.1:8888/callback";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "what's zack been listening to recently?")]
+pub struct Cli {
+    /// the config file
+    #[arg(short, long, global = true, default_value = "spotti.toml")]
+    pub config: PathBuf,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// run the web server
+    Serve,
+
+    /// refresh the global token, ingest recent listens once, and exit
+    PollOnce,
+
+    /// look up the albums and artists for listens from before spotti kept
+    /// them, or that were imported, so they show up on those pages too
+    Backfill,
+
+    /// write listens out, oldest first
+    Export {
+        /// where to write them, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        #[arg(short, long, value_enum, default_value_t)]
+        format: Format,
+
+        /// the first day to include, or an rfc3339 time
+        #[arg(long)]
+        from: Option<Bound>,
+
+        /// the last day to include, or an rfc3339 time to stop just before
+        #[arg(long)]
+        to: Option<Bound>,
+    },
+
+    /// read listens in the json lines format export writes by default,
+    /// skipping ones we already have. csv and scrobbler logs can't be read
+    /// back in
+    Import {
+        /// where to read them from, instead of stdin
+        input: Option<PathBuf>,
+    },
+
+    /// print some numbers about the database
+    Stats,
+
+    /// write a page of top tracks, artists and albums and such, like spotify
+    /// wrapped
+    Wrapped {
+        /// where to write it, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// the first day to include, or an rfc3339 time
+        #[arg(long)]
+        from: Option<Bound>,
+
+        /// the last day to include, or an rfc3339 time to stop just before
+        #[arg(long)]
+        to: Option<Bound>,
+    },
+
+    /// load the config file and print what it works out to, or everything
+    /// that's wrong with it
+    CheckConfig,
+
+    /// log in as the owner from somewhere without a browser
+    Auth {
+        /// we listen on this one's port for spotify to send us back to
+        #[arg(long, default_value = DEFAULT_AUTH_REDIRECT)]
+        redirect_uri: Url,
+    },
+
+    /// get a session key for scrobbling to last.fm, with the api key and
+    /// secret in [lastfm]
+    LastfmAuth,
+}
+
+impl Cli {
+    /// like `Cli::parse`, but `spotti config.toml` still means serve, like it
+    /// did before there were subcommands
+    pub fn parse_with_legacy() -> Cli {
+        let mut args = std::env::args_os().collect::<Vec<_>>();
+        if args.len() == 2 && Path::new(&args[1]).is_file() {
+            args = vec![
+                args[0].clone(),
+                "--config".into(),
+                args[1].clone(),
+                "serve".into(),
+            ];
+        }
+        Cli::parse_from(args)
+    }
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Config(ConfigError),
+    App(AppError),
+    Io(std::io::Error),
+
+    /// a line we couldn't import
+    Input {
+        line: usize,
+        err: serde_json::Error,
+    },
+
+    /// spotify sent us back without a code, or it wasn't the owner
+    Auth(String),
+
+    Tls(TlsError),
+
+    LastFm(LastFmError),
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Config(err) => write!(f, "{err}"),
+            CliError::App(err) => write!(f, "{}: {err}", err.context()),
+            CliError::Io(err) => write!(f, "{err}"),
+            CliError::Input { line, err } => write!(f, "line {line}: {err}"),
+            CliError::Auth(message) => write!(f, "{message}"),
+            CliError::Tls(err) => write!(f, "{err}"),
+            CliError::LastFm(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<ConfigError> for CliError {
+    fn from(err: ConfigError) -> Self {
+        CliError::Config(err)
+    }
+}
+
+impl From<AppError> for CliError {
+    fn from(err: AppError) -> Self {
+        CliError::App(err)
+    }
+}
+
+impl From<ExportError> for CliError {
+    fn from(err: ExportError) -> Self {
+        match err {
+            ExportError::Database(err) => CliError::App(AppError::database("export")(err)),
+            ExportError::Io(err) => CliError::Io(err),
+        }
+    }
+}
+
+impl From<TlsError> for CliError {
+    fn from(err: TlsError) -> Self {
+        CliError::Tls(err)
+    }
+}
+
+impl From<LastFmError> for CliError {
+    fn from(err: LastFmError) -> Self {
+        CliError::LastFm(err)
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(err: std::io::Error) -> Self {
+        CliError::Io(err)
+    }
+}
+
+pub fn load_config(path: &Path) -> Result<Config, CliError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        CliError::Io(std::io::Error::new(
+            err.kind(),
+            format!("{}: {err}", path.display()),
+        ))
+    })?;
+    Ok(crate::config::parse(&contents)?)
+}
+
+pub async fn run(cli: Cli) -> Result<(), CliError> {
+    let config = load_config(&cli.config)?;
+    tracing::debug!("{config:#?}");
+
+    // only once a command needs it, so checking the config doesn't touch the
+    // database
+    let mut opened = None;
+    let result = match cli.command {
+        Command::CheckConfig => check_config(&config, &mut std::io::stdout().lock()),
+
+        Command::Serve => {
+            let state = open(&mut opened, config).await?;
+            serve(state, &cli.config).await
+        }
+
+        Command::PollOnce => {
+            let state = open(&mut opened, config).await?;
+            let inserted = poll_once(&state).await?;
+            println!("{inserted} new listens");
+            Ok(())
+        }
+
+        Command::Backfill => {
+            let state = open(&mut opened, config).await?;
+            let (wanted, saved) = backfill(&state).await?;
+            println!("found {saved} of {wanted} tracks");
+            Ok(())
+        }
+
+        Command::Export {
+            output,
+            format,
+            from,
+            to,
+        } => {
+            let state = open(&mut opened, config).await?;
+            let range = Range { from, to };
+            let exported = match output {
+                Some(path) => {
+                    export::write(
+                        &state.pool,
+                        format,
+                        range,
+                        &mut std::io::BufWriter::new(std::fs::File::create(path)?),
+                    )
+                    .await?
+                }
+                None => {
+                    export::write(&state.pool, format, range, &mut std::io::stdout().lock()).await?
+                }
+            };
+            eprintln!("exported {exported} listens");
+            Ok(())
+        }
+
+        Command::Import { input } => {
+            let state = open(&mut opened, config).await?;
+            let (read, inserted) = match input {
+                Some(path) if path != Path::new("-") => {
+                    import(&state, std::io::BufReader::new(std::fs::File::open(path)?)).await?
+                }
+                _ => import(&state, std::io::stdin().lock()).await?,
+            };
+            println!("read {read} listens, {inserted} were new");
+            Ok(())
+        }
+
+        Command::Stats => {
+            let state = open(&mut opened, config).await?;
+            stats(&state, &mut std::io::stdout().lock()).await
+        }
+
+        Command::Wrapped { output, from, to } => {
+            let state = open(&mut opened, config).await?;
+            let range = Range { from, to };
+            match output {
+                Some(path) => {
+                    let mut out = std::io::BufWriter::new(std::fs::File::create(&path)?);
+                    wrapped(&state, range, &mut out).await?;
+                    out.flush()?;
+                    eprintln!("wrote {}", path.display());
+                    Ok(())
+                }
+                None => wrapped(&state, range, &mut std::io::stdout().lock()).await,
+            }
+        }
+
+        Command::Auth { redirect_uri } => {
+            let state = open(&mut opened, config).await?;
+            let host = redirect_uri.host_str().unwrap_or("127.0.0.1");
+            let port = redirect_uri.port_or_known_default().unwrap_or(80);
+            let listener = TcpListener::bind((host, port)).await?;
+            auth(
+                &state,
+                listener,
+                &redirect_uri,
+                tokio::io::BufReader::new(tokio::io::stdin()),
+            )
+            .await?;
+            println!("saved the owner's tokens, spotti will use them from now on");
+            Ok(())
+        }
+
+        Command::LastfmAuth => {
+            let state = open(&mut opened, config).await?;
+            let session_key =
+                lastfm_auth(&state, tokio::io::BufReader::new(tokio::io::stdin())).await?;
+            println!(
+                "add this to [lastfm] and restart spotti:\n\n    session_key = {session_key:?}"
+            );
+            Ok(())
+        }
+    };
+
+    if let Some(state) = opened {
+        state.shutdown().await;
+    }
+    result
+}
+
+/// the database and everything else a command needs, kept in `opened` so it
+/// gets shut down properly after
+async fn open(opened: &mut Option<AppState>, config: Config) -> Result<AppState, CliError> {
+    let state = AppState::new(config)
+        .await
+        .map_err(AppError::database("open database"))?;
+    *opened = Some(state.clone());
+    Ok(state)
+}
+
+pub async fn serve(state: AppState, config_path: &Path) -> Result<(), CliError> {
+    tracing::info!("starting {:?}", state.start_time);
+
+    #[cfg(feature = "discord")]
+    if let Some(discord) = &state.config.discord {
+        tokio::spawn(crate::discord::run(state.clone(), discord.token.clone()));
+    }
+
+    // whatever didn't make it out before we stopped last time
+    outbox::spawn_forward(&state);
+
+    let address = state.config.address;
+    let tls_config = state.config.tls.clone();
+    let base_url = state.config.get_new_url.clone();
+    let app = Reloadable::new(state.clone());
+    tokio::spawn(reload_on_hangup(app.clone(), config_path.to_owned()));
+    let app = app.router();
+
+    tracing::info!("listening at {:?}", address);
+    let listener = TcpListener::bind(address).await?;
+
+    let Some(tls_config) = tls_config else {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+        return Ok(());
+    };
+
+    let tls = Tls::new(tls_config.clone())?;
+    if let Some(redirect_address) = tls_config.redirect_address {
+        tracing::info!("redirecting to https from {:?}", redirect_address);
+        let listener = TcpListener::bind(redirect_address).await?;
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, tls::redirect(base_url)).await {
+                error::report(&state, &AppError::internal("https redirect")(err)).await;
+            }
+        });
+    }
+    tls::serve(listener, Arc::new(tls), app, shutdown_signal()).await?;
+    Ok(())
+}
+
+/// ctrl-c or SIGTERM, like systemd sends
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::warn!("can't shut down cleanly on SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    tracing::info!("shutting down, finishing up what's in flight");
+}
+
+/// `kill -HUP` to pick up changes to the config file. a bad file, or one that
+/// changes something we can't, leaves everything as it was
+async fn reload_on_hangup(app: Reloadable, config_path: PathBuf) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::warn!("can't reload on SIGHUP: {err}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        tracing::info!("reloading {}", config_path.display());
+        match load_config(&config_path).and_then(|config| Ok(app.reload(config)?)) {
+            Ok(changes) if changes.is_empty() => tracing::info!("nothing changed"),
+            Ok(changes) => tracing::info!("changed: {}", changes.join(", ")),
+            Err(err) => tracing::error!("not reloading: {err}"),
+        }
+    }
+}
+
+pub fn check_config(config: &Config, out: &mut impl Write) -> Result<(), CliError> {
+    writeln!(out, "database: {}", config.db_file)?;
+    writeln!(out, "listening on: {}", config.address)?;
+    if let Some(tls) = &config.tls {
+        // a bad pem only shows up once we try to use it
+        Tls::new(tls.clone())?;
+        writeln!(out, "certificate: {}", tls.cert.display())?;
+        if let Some(redirect_address) = tls.redirect_address {
+            writeln!(out, "redirecting to https from: {redirect_address}")?;
+        }
+    }
+    for (name, url) in [
+        ("authorize", &config.authorize_url),
+        ("refresh", &config.refresh_url),
+        ("get new", &config.get_new_url),
+        ("show all", &config.show_all_url),
+        ("uptime", &config.uptime_url),
+        ("errors", &config.errors_url),
+        ("health", &config.health_url),
+        ("metrics", &config.metrics_url),
+        ("export", &config.export_url),
+        ("search", &config.search_url),
+        ("track", &config.track_url),
+        ("album", &config.album_url),
+        ("artist", &config.artist_url),
+        ("stats", &config.stats_url),
+        ("wrapped", &config.wrapped_url),
+    ] {
+        writeln!(out, "{name}: {url}")?;
+    }
+    writeln!(out, "alert sinks: {}", config.alerts.sinks.len())?;
+    writeln!(out, "ok")?;
+    Ok(())
+}
+
+/// returns how many listens were read, and how many of those were new. all or
+/// nothing, so a bad line halfway through doesn't leave half an import behind
+pub async fn import(state: &AppState, input: impl BufRead) -> Result<(u64, u64), CliError> {
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(AppError::database("import start xact"))?;
+
+    let (mut read, mut inserted) = (0, 0);
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let listen: SongRecord =
+            serde_json::from_str(&line).map_err(|err| CliError::Input { line: i + 1, err })?;
+        read += 1;
+        if db::insert_listen(&mut *tx, &listen)
+            .await
+            .map_err(AppError::database("import insert"))?
+        {
+            inserted += 1;
+        }
+    }
+
+    tx.commit()
+        .await
+        .map_err(AppError::database("import commit"))?;
+    Ok((read, inserted))
+}
+
+/// like the server's poll, but nobody's watching a cron job, so a failure has
+/// to get to the alerts before we exit
+pub async fn poll_once(state: &AppState) -> Result<u64, CliError> {
+    match server::poll_once(state).await {
+        Ok(inserted) => Ok(inserted),
+        Err(err) => {
+            if let Some(alert) = error::report(state, &err).await {
+                let _ = alert.await;
+            }
+            Err(err.into())
+        }
+    }
+}
+
+/// gets what spotify knows about every track we only have listens for.
+/// returns how many there were, and how many spotify knew
+pub async fn backfill(state: &AppState) -> Result<(usize, usize), CliError> {
+    let ids = db::uncatalogued_tracks(&state.pool)
+        .await
+        .map_err(AppError::database("uncatalogued tracks"))?;
+    if ids.is_empty() {
+        return Ok((0, 0));
+    }
+
+    server::refresh_global(state).await?;
+    let access_token = state
+        .global_auth
+        .read()
+        .map_err(AppError::internal("lock global auth backfill"))?
+        .as_ref()
+        .ok_or_else(|| AppError::unauthorized("backfill"))?
+        .0
+        .access_token
+        .clone();
+
+    let mut saved = 0;
+    for ids in ids.chunks(TRACKS_AT_ONCE) {
+        let tracks = state
+            .spotify
+            .tracks(&access_token, ids)
+            .await
+            .map_err(AppError::spotify("tracks"))?;
+        let mut tx = state
+            .pool
+            .begin()
+            .await
+            .map_err(AppError::database("start xact"))?;
+        for track in &tracks {
+            db::save_track(&mut tx, track)
+                .await
+                .map_err(AppError::database("save track"))?;
+        }
+        tx.commit()
+            .await
+            .map_err(AppError::database("xact commit"))?;
+        saved += tracks.len();
+    }
+    Ok((ids.len(), saved))
+}
+
+pub async fn stats(state: &AppState, out: &mut impl Write) -> Result<(), CliError> {
+    let stats = db::stats(&state.pool)
+        .await
+        .map_err(AppError::database("stats"))?;
+    let top = db::top_tracks(&state.pool, None, None, 10)
+        .await
+        .map_err(AppError::database("stats top"))?;
+    let time = listening_stats(&state.pool, Range::default())
+        .await
+        .map_err(AppError::database("stats time"))?
+        .total;
+
+    writeln!(out, "listens: {}", stats.listens)?;
+    writeln!(out, "tracks: {}", stats.tracks)?;
+    writeln!(out, "artists: {}", stats.artists)?;
+    writeln!(
+        out,
+        "first listen: {}",
+        stats.first.as_deref().unwrap_or("-")
+    )?;
+    writeln!(out, "last listen: {}", stats.last.as_deref().unwrap_or("-"))?;
+    writeln!(out, "time listened: {}", stats::format_ms(time.ms))?;
+    if !top.is_empty() {
+        writeln!(out, "top tracks:")?;
+        for (i, track) in top.iter().enumerate() {
+            writeln!(
+                out,
+                "{:>4}. {} by {} ({})",
+                i + 1,
+                track.name,
+                track.artist,
+                track.plays
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn wrapped(state: &AppState, range: Range, out: &mut impl Write) -> Result<(), CliError> {
+    let report = wrapped::wrapped(&state.pool, range)
+        .await
+        .map_err(AppError::database("wrapped"))?;
+    out.write_all(wrapped::html(&report, &wrapped::describe(&range)).as_bytes())?;
+    Ok(())
+}
+
+/// have the owner allow scrobbling, and return the session key for it
+pub async fn lastfm_auth(
+    state: &AppState,
+    enter: impl AsyncBufRead + Unpin,
+) -> Result<String, CliError> {
+    let Some(config) = &state.config.lastfm else {
+        return Err(CliError::Auth("there's no [lastfm] in the config".into()));
+    };
+    let client = LastFmClient::new(state.http.clone(), config);
+
+    let token = client.get_token().await?;
+    println!(
+        "allow spotti to scrobble here:\n\n    {}\n",
+        client.auth_url(&token)
+    );
+    println!("then press enter");
+    enter.lines().next_line().await?;
+
+    Ok(client.get_session(&token).await?)
+}
+
+/// print the authorize url, then wait for spotify to send the browser back to
+/// `listener`. if the browser is on another machine that can't reach us, the
+/// url it ended up at can be pasted into `paste` instead.
+pub async fn auth(
+    state: &AppState,
+    listener: TcpListener,
+    redirect_uri: &Url,
+    paste: impl AsyncBufRead + Unpin,
+) -> Result<(), CliError> {
+    let authorize_url = state
+        .spotify
+        .authorize_url(redirect_uri)
+        .map_err(AppError::spotify("auth url"))?;
+    println!("log in as the owner here:\n\n    {authorize_url}\n");
+    println!("if you end up at a page that doesn't load, paste its url here and press enter");
+
+    let (send, mut receive) = mpsc::channel(1);
+    let app = Router::new()
+        .route(redirect_uri.path(), routing::get(callback))
+        .with_state(send);
+    let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let mut lines = paste.lines();
+    let mut pasting = true;
+    let code = loop {
+        tokio::select! {
+            Some(code) = receive.recv() => break code,
+            line = lines.next_line(), if pasting => match line {
+                Ok(Some(line)) => {
+                    if let Some(code) = code_from(&line) {
+                        break code;
+                    }
+                }
+                // nobody's there, keep waiting for the browser
+                _ => pasting = false,
+            },
+        }
+    };
+    server.abort();
+    let code = code?;
+
+    let tokens = state
+        .spotify
+        .request_token(&code, redirect_uri)
+        .await
+        .map_err(AppError::spotify("token"))?;
+    let me = state
+        .spotify
+        .me(&tokens.access_token)
+        .await
+        .map_err(AppError::spotify("get me"))?;
+    if me.id != crate::ME {
+        return Err(CliError::Auth(format!(
+            "logged in as {}, which isn't the owner",
+            me.id
+        )));
+    }
+
+    server::set_global_auth(state, tokens).await?;
+    Ok(())
+}
+
+/// the code out of a pasted redirect url, or just the code on its own
+fn code_from(line: &str) -> Option<Result<String, CliError>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let Ok(url) = Url::parse(line) else {
+        return Some(Ok(line.into()));
+    };
+    let query = url.query_pairs().collect::<HashMap<_, _>>();
+    Some(code_from_query(
+        query.get("code").map(|code| code.to_string()),
+        query.get("error").map(|error| error.to_string()),
+    ))
+}
+
+fn code_from_query(code: Option<String>, error: Option<String>) -> Result<String, CliError> {
+    match (code, error) {
+        (Some(code), _) => Ok(code),
+        (None, Some(error)) => Err(CliError::Auth(format!("spotify said {error}"))),
+        (None, None) => Err(CliError::Auth(String::from(
+            "spotify didn't send a code back",
+        ))),
+    }
+}
+
+async fn callback(
+    State(send): State<mpsc::Sender<Result<String, CliError>>>,
+    Query(mut query): Query<HashMap<String, String>>,
+) -> &'static str {
+    let code = code_from_query(query.remove("code"), query.remove("error"));
+    let reply = if code.is_ok() {
+        "got it, you can close this and go back to the terminal"
+    } else {
+        "that didn't work, check the terminal"
+    };
+    let _ = send.send(code).await;
+    reply
+}
@@ -0,0 +1,525 @@
+//! turning the config file into a `Config`, and everything that can go wrong
+//! along the way
+
+use crate::{Config, StringConfig};
+use std::{convert::TryFrom, path::PathBuf};
+use toml::{Table, Value};
+
+/// the keys StringConfig can't do without. serde only tells us about the first
+/// one that's missing, so we look for all of them ourselves
+const REQUIRED: &[&str] = &[
+    "db_file",
+    "client_id",
+    "client_secret",
+    "base_url",
+    "authorize_endpoint",
+    "refresh_endpoint",
+    "get_new_endpoint",
+    "show_all_endpoint",
+    "uptime_endpoint",
+    "get_new_limit",
+    "address",
+];
+
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Integer,
+    /// spelled out in toml, like `[{ type = "webhook", url = "..." }]`
+    Toml,
+}
+
+/// everything in StringConfig, as `SPOTTI_` and then the key in capitals, with
+/// a dot for tables. `discord.token` is `SPOTTI_DISCORD_TOKEN`
+const ENV_KEYS: &[(&str, Kind)] = &[
+    ("db_file", Kind::String),
+    ("error_file", Kind::String),
+    ("bot_pidfile", Kind::String),
+    ("client_id", Kind::String),
+    ("client_secret", Kind::String),
+    ("client_secret_file", Kind::String),
+    ("base_url", Kind::String),
+    ("path_prefix", Kind::String),
+    ("authorize_endpoint", Kind::String),
+    ("refresh_endpoint", Kind::String),
+    ("get_new_endpoint", Kind::String),
+    ("show_all_endpoint", Kind::String),
+    ("uptime_endpoint", Kind::String),
+    ("errors_endpoint", Kind::String),
+    ("health_endpoint", Kind::String),
+    ("metrics_endpoint", Kind::String),
+    ("export_endpoint", Kind::String),
+    ("search_endpoint", Kind::String),
+    ("track_endpoint", Kind::String),
+    ("album_endpoint", Kind::String),
+    ("artist_endpoint", Kind::String),
+    ("stats_endpoint", Kind::String),
+    ("wrapped_endpoint", Kind::String),
+    ("get_new_limit", Kind::Integer),
+    ("stale_after_secs", Kind::Integer),
+    ("address", Kind::String),
+    ("tls.cert", Kind::String),
+    ("tls.key", Kind::String),
+    ("tls.redirect_address", Kind::String),
+    ("spotify_api_url", Kind::String),
+    ("spotify_accounts_url", Kind::String),
+    ("alerts", Kind::Toml),
+    ("alert_dedup_secs", Kind::Integer),
+    ("alert_max_per_hour", Kind::Integer),
+    ("discord.token", Kind::String),
+    ("discord.token_file", Kind::String),
+    ("discord.owner", Kind::Integer),
+    ("listenbrainz.token", Kind::String),
+    ("listenbrainz.token_file", Kind::String),
+    ("listenbrainz.api_url", Kind::String),
+    ("lastfm.api_key", Kind::String),
+    ("lastfm.api_secret", Kind::String),
+    ("lastfm.api_secret_file", Kind::String),
+    ("lastfm.session_key", Kind::String),
+    ("lastfm.session_key_file", Kind::String),
+    ("lastfm.api_url", Kind::String),
+];
+
+/// these need a restart to change: the listener, the pool, and the discord
+/// connection all get set up once
+const IMMUTABLE: &[&str] = &["db_file", "address", "tls", "discord"];
+
+/// these can come from `<key>_file` instead, for systemd credentials and
+/// container secrets
+const SECRETS: &[&str] = &[
+    "client_secret",
+    "discord.token",
+    "listenbrainz.token",
+    "lastfm.api_secret",
+    "lastfm.session_key",
+];
+
+#[derive(Debug)]
+pub enum ConfigProblem {
+    /// not even toml
+    Syntax(toml::de::Error),
+
+    /// an environment variable that doesn't fit the key it's for
+    BadEnv {
+        var: String,
+        message: String,
+    },
+
+    /// both `client_secret` and `client_secret_file` in the file
+    SecretConflict(&'static str),
+
+    SecretFile {
+        key: String,
+        path: String,
+        err: std::io::Error,
+    },
+
+    MissingKey(&'static str),
+
+    /// there, but the wrong type
+    Invalid(toml::de::Error),
+
+    DbFileMissing(PathBuf),
+
+    BadUrl {
+        key: &'static str,
+        url: String,
+        err: url::ParseError,
+    },
+
+    /// like `mailto:zack@example.com`, there's no path to put endpoints on
+    CannotBeABase {
+        key: &'static str,
+        url: String,
+    },
+
+    NoDomain(String),
+
+    BadAddress {
+        address: String,
+        err: std::net::AddrParseError,
+    },
+
+    BadEndpoint {
+        key: &'static str,
+        endpoint: String,
+    },
+
+    TlsFileMissing {
+        key: &'static str,
+        path: PathBuf,
+    },
+
+    /// serving https ourselves, but everything links to http, which just
+    /// redirects back to the same place
+    TlsWithoutHttps(String),
+
+    /// the router can't have two handlers on one path
+    DuplicateEndpoint {
+        path: String,
+        keys: Vec<&'static str>,
+    },
+
+    /// can't be changed by reloading
+    Immutable(&'static str),
+
+    /// counting from 1, in the order they're in the file
+    BadAlert {
+        index: usize,
+        err: url::ParseError,
+    },
+}
+
+impl std::fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigProblem::Syntax(err) => write!(f, "couldn't parse the file: {err}"),
+            ConfigProblem::BadEnv { var, message } => write!(f, "${var}: {message}"),
+            ConfigProblem::SecretConflict(key) => {
+                write!(f, "only one of `{key}` and `{key}_file` please")
+            }
+            ConfigProblem::SecretFile { key, path, err } => {
+                write!(f, "`{key}` {path:?} couldn't be read: {err}")
+            }
+            ConfigProblem::MissingKey(key) => write!(f, "missing `{key}`"),
+            ConfigProblem::Invalid(err) => write!(f, "{err}"),
+            ConfigProblem::DbFileMissing(path) => {
+                write!(f, "`db_file` {} does not exist", path.display())
+            }
+            ConfigProblem::BadUrl { key, url, err } => {
+                write!(f, "`{key}` {url:?} isn't a url: {err}")
+            }
+            ConfigProblem::CannotBeABase { key, url } => {
+                write!(f, "`{key}` {url:?} can't have endpoints under it")
+            }
+            ConfigProblem::NoDomain(url) => write!(f, "`base_url` {url:?} needs a domain name"),
+            ConfigProblem::BadAddress { address, err } => {
+                write!(f, "`address` {address:?} isn't an ip and port: {err}")
+            }
+            ConfigProblem::BadEndpoint { key, endpoint } => {
+                write!(f, "`{key}` {endpoint:?} can't have . or .. in it")
+            }
+            ConfigProblem::TlsFileMissing { key, path } => {
+                write!(f, "`{key}` {} does not exist", path.display())
+            }
+            ConfigProblem::TlsWithoutHttps(url) => {
+                write!(f, "`base_url` {url:?} has to be https to use `tls`")
+            }
+            ConfigProblem::DuplicateEndpoint { path, keys } => {
+                write!(f, "{} would all be at {path}", keys.join(", "))
+            }
+            ConfigProblem::Immutable(key) => {
+                write!(f, "`{key}` can't change without a restart")
+            }
+            ConfigProblem::BadAlert { index, err } => {
+                write!(f, "alert #{index} has a bad url: {err}")
+            }
+        }
+    }
+}
+
+/// everything wrong with a config file, so it only takes one try to fix
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.problems.as_slice() {
+            [problem] => write!(f, "bad config: {problem}"),
+            problems => {
+                write!(f, "{} problems with the config:", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigProblem> for ConfigError {
+    fn from(problem: ConfigProblem) -> Self {
+        ConfigError {
+            problems: vec![problem],
+        }
+    }
+}
+
+macro_rules! changed {
+    ($old:expr, $new:expr, $($field:ident),* $(,)?) => {{
+        let mut changed = Vec::new();
+        $(
+            if $old.$field != $new.$field {
+                changed.push(stringify!($field));
+            }
+        )*
+        changed
+    }};
+}
+
+impl Config {
+    /// the fields that are different in `new`
+    pub fn changes(&self, new: &Config) -> Vec<&'static str> {
+        changed!(
+            self,
+            new,
+            db_file,
+            client_id,
+            client_secret,
+            authorize_url,
+            refresh_url,
+            get_new_url,
+            show_all_url,
+            uptime_url,
+            errors_url,
+            health_url,
+            metrics_url,
+            export_url,
+            search_url,
+            track_url,
+            album_url,
+            artist_url,
+            stats_url,
+            wrapped_url,
+            get_new_limit,
+            stale_after,
+            address,
+            tls,
+            spotify_api_url,
+            spotify_accounts_url,
+            alerts,
+            discord,
+            listenbrainz,
+            lastfm,
+        )
+    }
+
+    /// what's changed in `new`, unless it's something that can't change
+    /// while we're running
+    pub fn reloadable_changes(&self, new: &Config) -> Result<Vec<&'static str>, ConfigError> {
+        let changes = self.changes(new);
+        let problems = changes
+            .iter()
+            .filter(|field| IMMUTABLE.contains(field))
+            .map(|field| ConfigProblem::Immutable(field))
+            .collect::<Vec<_>>();
+        if problems.is_empty() {
+            Ok(changes)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+}
+
+fn env_var(key: &str) -> String {
+    format!("SPOTTI_{}", key.replace('.', "_").to_uppercase())
+}
+
+/// the table a dotted key lives in, and its last part
+fn locate<'a, 'k>(table: &'a mut Table, key: &'k str) -> Option<(&'a mut Table, &'k str)> {
+    match key.split_once('.') {
+        Some((outer, inner)) => {
+            let outer = table
+                .entry(outer)
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()?;
+            Some((outer, inner))
+        }
+        None => Some((table, key)),
+    }
+}
+
+/// whatever's set in the environment wins over the file. setting a secret
+/// from the environment also wins over its `_file` in the file, and the other
+/// way around.
+fn apply_env(
+    table: &mut Table,
+    env: &impl Fn(&str) -> Option<String>,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    for (key, kind) in ENV_KEYS {
+        let var = env_var(key);
+        let Some(raw) = env(&var) else {
+            continue;
+        };
+
+        let value = match kind {
+            Kind::String => Ok(Value::String(raw)),
+            Kind::Integer => raw
+                .trim()
+                .parse()
+                .map(Value::Integer)
+                .map_err(|err: std::num::ParseIntError| err.to_string()),
+            Kind::Toml => format!("value = {raw}")
+                .parse::<Table>()
+                .map(|mut table| table.remove("value").unwrap())
+                .map_err(|err| err.to_string()),
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(message) => {
+                problems.push(ConfigProblem::BadEnv { var, message });
+                continue;
+            }
+        };
+
+        let Some((table, name)) = locate(table, key) else {
+            problems.push(ConfigProblem::BadEnv {
+                var,
+                message: format!(
+                    "`{}` in the file isn't a table",
+                    key.split('.').next().unwrap()
+                ),
+            });
+            continue;
+        };
+        if SECRETS.contains(key) {
+            table.remove(&format!("{name}_file"));
+        } else if let Some(secret) = key
+            .strip_suffix("_file")
+            .filter(|key| SECRETS.contains(key))
+        {
+            table.remove(secret.rsplit('.').next().unwrap());
+        }
+        table.insert(name.into(), value);
+    }
+}
+
+/// swap `<secret>_file` for what's in the file
+fn read_secrets(table: &mut Table, problems: &mut Vec<ConfigProblem>) {
+    for secret in SECRETS {
+        let Some((table, name)) = locate(table, secret) else {
+            continue;
+        };
+        let file_key = format!("{name}_file");
+        let Some(path) = table.remove(&file_key) else {
+            continue;
+        };
+
+        if table.contains_key(name) {
+            problems.push(ConfigProblem::SecretConflict(secret));
+            continue;
+        }
+
+        let Value::String(path) = path else {
+            problems.push(ConfigProblem::SecretFile {
+                key: format!("{secret}_file"),
+                path: path.to_string(),
+                err: std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a string"),
+            });
+            continue;
+        };
+        match std::fs::read_to_string(&path) {
+            // editors and echo like to leave a newline on the end
+            Ok(contents) => {
+                table.insert(name.into(), Value::String(contents.trim_end().into()));
+            }
+            Err(err) => problems.push(ConfigProblem::SecretFile {
+                key: format!("{secret}_file"),
+                path,
+                err,
+            }),
+        }
+    }
+
+    // don't leave an empty [discord] behind if looking for secrets made one up
+    for secret in SECRETS {
+        let Some((outer, _)) = secret.split_once('.') else {
+            continue;
+        };
+        if table
+            .get(outer)
+            .and_then(Value::as_table)
+            .is_some_and(Table::is_empty)
+        {
+            table.remove(outer);
+        }
+    }
+}
+
+/// the contents of a config file, with `SPOTTI_*` environment variables
+/// layered on top
+pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+    parse_with_env(contents, |var| std::env::var(var).ok())
+}
+
+pub fn parse_with_env(
+    contents: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Config, ConfigError> {
+    let mut table = contents.parse::<Table>().map_err(ConfigProblem::Syntax)?;
+
+    let mut problems = Vec::new();
+    apply_env(&mut table, &env, &mut problems);
+    read_secrets(&mut table, &mut problems);
+
+    // keep going with stand-ins for anything missing, so the rest of the
+    // config still gets checked
+    for key in REQUIRED {
+        if table.contains_key(*key) {
+            continue;
+        }
+        // a secret whose file couldn't be read has already been reported
+        let unreadable = problems.iter().any(|problem| {
+            matches!(problem, ConfigProblem::SecretFile { key: file_key, .. }
+                if file_key.strip_suffix("_file") == Some(*key))
+        });
+        if !unreadable {
+            problems.push(ConfigProblem::MissingKey(key));
+        }
+        table.insert(key.to_string(), stand_in(key));
+    }
+
+    // serde stops at the first key that's the wrong type, so try each one on
+    // its own to find all of them
+    let stand_ins = REQUIRED
+        .iter()
+        .map(|key| (key.to_string(), stand_in(key)))
+        .collect::<Table>();
+    let keys = table.keys().cloned().collect::<Vec<_>>();
+    for key in keys {
+        let mut alone = stand_ins.clone();
+        alone.insert(key.clone(), table[&key].clone());
+        if let Err(err) = Value::Table(alone).try_into::<StringConfig>() {
+            problems.push(ConfigProblem::Invalid(err));
+            match REQUIRED.iter().find(|required| **required == key) {
+                Some(required) => table.insert(key, stand_in(required)),
+                None => table.remove(&key),
+            };
+        }
+    }
+
+    let string_config: StringConfig = match Value::Table(table).try_into() {
+        Ok(string_config) => string_config,
+        Err(err) => {
+            problems.push(ConfigProblem::Invalid(err));
+            return Err(ConfigError { problems });
+        }
+    };
+    match Config::try_from(string_config) {
+        Ok(config) if problems.is_empty() => Ok(config),
+        Ok(_) => Err(ConfigError { problems }),
+        Err(err) => {
+            problems.extend(err.problems);
+            Err(ConfigError { problems })
+        }
+    }
+}
+
+/// something for a required key that passes its own checks, so only the real
+/// problem with it gets reported
+fn stand_in(key: &str) -> Value {
+    match key {
+        // somewhere that exists
+        "db_file" => Value::from("."),
+        "base_url" => Value::from("http://spotti.invalid/"),
+        "get_new_limit" => Value::from(1),
+        "address" => Value::from("127.0.0.1:0"),
+        // endpoints named after themselves can't collide
+        key => Value::from(key),
+    }
+}
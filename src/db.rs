@@ -0,0 +1,763 @@
+use crate::{Context, SongRecord, TokenPair, Track};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use futures_util::stream::BoxStream;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    types::Json,
+    SqliteConnection, SqliteExecutor, SqlitePool,
+};
+use std::collections::HashMap;
+
+/// sqlite only lets one writer in at a time, so there's no point in having a
+/// lot of connections around. WAL means readers don't have to wait for it.
+const MAX_CONNECTIONS: u32 = 4;
+
+pub async fn connect(db_file: &str) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::new()
+        .filename(db_file)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(std::time::Duration::from_secs(5));
+
+    SqlitePoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .min_connections(1)
+        .acquire_timeout(std::time::Duration::from_secs(10))
+        .connect_with(options)
+        .await
+}
+
+pub async fn migrate(pool: &SqlitePool) -> Result<(), sqlx::migrate::MigrateError> {
+    sqlx::migrate!().run(pool).await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ErrorRecord {
+    pub at: String,
+    pub context: String,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ErrorCount {
+    pub context: String,
+    pub message: String,
+    pub count: i64,
+    pub first: String,
+    pub last: String,
+}
+
+pub async fn record_error(
+    pool: &SqlitePool,
+    context: &str,
+    message: &str,
+    details: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "insert into errors (context, message, details) values ($1, $2, $3)",
+        context,
+        message,
+        details,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn recent_errors(pool: &SqlitePool, limit: u32) -> Result<Vec<ErrorRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        ErrorRecord,
+        "select at, context, message, details from errors order by id desc limit $1",
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// how many times each distinct error has happened
+pub async fn error_counts(pool: &SqlitePool) -> Result<Vec<ErrorCount>, sqlx::Error> {
+    sqlx::query_as!(
+        ErrorCount,
+        r#"select
+            context as "context!",
+            message as "message!",
+            count(*) as "count!: i64",
+            min(at) as "first!: String",
+            max(at) as "last!: String"
+        from errors
+        group by context, message
+        order by max(at) desc"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// returns whether it was new
+pub async fn insert_listen(
+    executor: impl SqliteExecutor<'_>,
+    listen: &SongRecord,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "insert or ignore into songs values ($1, $2, $3, $4, $5)",
+        listen.name,
+        listen.album,
+        listen.artist,
+        listen.date,
+        listen.id,
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// remember which album and artists a track is on, under spotify's ids
+pub async fn save_track(
+    connection: &mut SqliteConnection,
+    track: &Track,
+) -> Result<(), sqlx::Error> {
+    let album = &track.album;
+    let images = serde_json::to_string(&album.images).unwrap();
+    sqlx::query!(
+        "insert into albums (id, name, release_date, release_date_precision, images)
+        values ($1, $2, $3, $4, $5)
+        on conflict (id) do update set
+            name = excluded.name,
+            release_date = excluded.release_date,
+            release_date_precision = excluded.release_date_precision,
+            images = excluded.images",
+        album.id,
+        album.name,
+        album.release_date,
+        album.release_date_precision,
+        images,
+    )
+    .execute(&mut *connection)
+    .await?;
+    sqlx::query!(
+        "insert into tracks (
+            id, name, album_id, duration_ms, explicit, popularity, track_number, disc_number, isrc
+        )
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        on conflict (id) do update set
+            name = excluded.name,
+            album_id = excluded.album_id,
+            duration_ms = excluded.duration_ms,
+            explicit = excluded.explicit,
+            popularity = excluded.popularity,
+            track_number = excluded.track_number,
+            disc_number = excluded.disc_number,
+            isrc = excluded.isrc",
+        track.id,
+        track.name,
+        album.id,
+        track.duration_ms,
+        track.explicit,
+        track.popularity,
+        track.track_number,
+        track.disc_number,
+        track.external_ids.isrc,
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query!("delete from track_artists where track_id = $1", track.id)
+        .execute(&mut *connection)
+        .await?;
+    for (position, artist) in track.artists.iter().enumerate() {
+        sqlx::query!(
+            "insert into artists (id, name) values ($1, $2)
+            on conflict (id) do update set name = excluded.name",
+            artist.id,
+            artist.name,
+        )
+        .execute(&mut *connection)
+        .await?;
+        let position = position as i64;
+        sqlx::query!(
+            "insert into track_artists (track_id, position, artist_id) values ($1, $2, $3)",
+            track.id,
+            position,
+            artist.id,
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+    Ok(())
+}
+
+/// where a listen was played from
+pub async fn save_context(
+    executor: impl SqliteExecutor<'_>,
+    date: &str,
+    context: &Context,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "insert or replace into listen_contexts (date, type, uri, href) values ($1, $2, $3, $4)",
+        date,
+        context.r#type,
+        context.uri,
+        context.href,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// where a track's album and artists link to, if we know
+#[derive(Debug, Default)]
+pub struct TrackLinks {
+    pub album_id: Option<String>,
+    /// id and name
+    pub artists: Vec<(String, String)>,
+}
+
+/// links for the tracks with these ids
+pub async fn track_links(
+    pool: &SqlitePool,
+    ids: &[&str],
+) -> Result<HashMap<String, TrackLinks>, sqlx::Error> {
+    let ids = serde_json::to_string(ids).unwrap();
+    let rows = sqlx::query!(
+        r#"select
+            tracks.id as "id!",
+            tracks.album_id,
+            artists.id as "artist_id?",
+            artists.name as "artist_name?"
+        from tracks
+        left join track_artists on track_artists.track_id = tracks.id
+        left join artists on artists.id = track_artists.artist_id
+        where tracks.id in (select value from json_each($1))
+        order by tracks.id, track_artists.position"#,
+        ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut links = HashMap::<String, TrackLinks>::new();
+    for row in rows {
+        let track = links.entry(row.id).or_default();
+        track.album_id = Some(row.album_id);
+        if let (Some(id), Some(name)) = (row.artist_id, row.artist_name) {
+            track.artists.push((id, name));
+        }
+    }
+    Ok(links)
+}
+
+/// every listen of a track, most recent first
+pub async fn track_listens(pool: &SqlitePool, id: &str) -> Result<Vec<SongRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        SongRecord,
+        "select * from songs where id = $1 order by datetime(date) desc",
+        id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// every listen of anything on an album, most recent first
+pub async fn album_listens(pool: &SqlitePool, id: &str) -> Result<Vec<SongRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        SongRecord,
+        "select songs.name, songs.album, songs.artist, songs.date, songs.id
+        from songs join tracks on tracks.id = songs.id
+        where tracks.album_id = $1
+        order by datetime(songs.date) desc",
+        id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// every listen of anything an artist is on, most recent first
+pub async fn artist_listens(pool: &SqlitePool, id: &str) -> Result<Vec<SongRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        SongRecord,
+        "select songs.name, songs.album, songs.artist, songs.date, songs.id
+        from songs join track_artists on track_artists.track_id = songs.id
+        where track_artists.artist_id = $1
+        order by datetime(songs.date) desc",
+        id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn album_name(pool: &SqlitePool, id: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!("select name from albums where id = $1", id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn artist_name(pool: &SqlitePool, id: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!("select name from artists where id = $1", id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// oldest first, from `from` up to but not including `to`, a row at a time
+pub fn listens_between<'a>(
+    pool: &'a SqlitePool,
+    from: &'a Option<String>,
+    to: &'a Option<String>,
+) -> BoxStream<'a, Result<SongRecord, sqlx::Error>> {
+    sqlx::query_as!(
+        SongRecord,
+        "select * from songs
+        where ($1 is null or datetime(date) >= datetime($1))
+            and ($2 is null or datetime(date) < datetime($2))
+        order by datetime(date) asc",
+        // reborrowed, so the stream can outlive this function
+        *from,
+        *to
+    )
+    .fetch(pool)
+}
+
+pub async fn last_listen(pool: &SqlitePool) -> Result<Option<SongRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        SongRecord,
+        "select * from songs order by datetime(date) desc limit 1"
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TopTrack {
+    pub name: String,
+    pub artist: String,
+    pub plays: i64,
+}
+
+/// most played tracks between `from` and up to but not including `to`,
+/// either of which can be left open
+pub async fn top_tracks(
+    pool: &SqlitePool,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: u32,
+) -> Result<Vec<TopTrack>, sqlx::Error> {
+    sqlx::query_as!(
+        TopTrack,
+        r#"select
+            name as "name!",
+            artist as "artist!",
+            count(*) as "plays!: i64"
+        from songs
+        where ($1 is null or datetime(date) >= datetime($1))
+            and ($2 is null or datetime(date) < datetime($2))
+        group by name, artist
+        order by count(*) desc, max(datetime(date)) desc
+        limit $3"#,
+        from,
+        to,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SearchResult {
+    pub name: Option<String>,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub id: Option<String>,
+    pub plays: i64,
+    /// every time it was played, most recent first
+    pub played_at: Json<Vec<String>>,
+}
+
+/// tracks matching an fts5 query, most recently played first
+pub async fn search(
+    pool: &SqlitePool,
+    query: &str,
+    limit: u32,
+) -> Result<Vec<SearchResult>, sqlx::Error> {
+    sqlx::query_as!(
+        SearchResult,
+        r#"select
+            songs.name,
+            songs.album,
+            songs.artist,
+            songs.id,
+            count(*) as "plays!: i64",
+            json_group_array(songs.date order by datetime(songs.date) desc) as "played_at!: Json<Vec<String>>"
+        from songs_search
+        join songs on songs.rowid = songs_search.rowid
+        where songs_search match $1 and songs.date is not null
+        group by coalesce(songs.id, songs.name || songs.artist)
+        order by max(datetime(songs.date)) desc
+        limit $2"#,
+        query,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Stats {
+    pub listens: i64,
+    pub tracks: i64,
+    pub artists: i64,
+    pub first: Option<String>,
+    pub last: Option<String>,
+}
+
+pub async fn stats(pool: &SqlitePool) -> Result<Stats, sqlx::Error> {
+    sqlx::query_as!(
+        Stats,
+        r#"select
+            count(*) as "listens!: i64",
+            count(distinct coalesce(id, name || artist)) as "tracks!: i64",
+            count(distinct artist) as "artists!: i64",
+            min(date) as "first: String",
+            max(date) as "last: String"
+        from songs"#
+    )
+    .fetch_one(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ListeningTime {
+    /// however `strftime` formatted it
+    pub period: String,
+    pub listens: i64,
+    pub ms: i64,
+    pub minutes: f64,
+    /// listens we don't know the length of, so they aren't in `ms`
+    pub untimed: i64,
+}
+
+/// time listened, grouped by `strftime(format, date)` in utc, between `from`
+/// and up to but not including `to`
+pub async fn listening_time(
+    pool: &SqlitePool,
+    format: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<ListeningTime>, sqlx::Error> {
+    sqlx::query_as!(
+        ListeningTime,
+        r#"select
+            strftime($1, songs.date) as "period!: String",
+            count(*) as "listens!: i64",
+            coalesce(sum(tracks.duration_ms), 0) as "ms!: i64",
+            round(coalesce(sum(tracks.duration_ms), 0) / 60000.0, 1) as "minutes!: f64",
+            count(*) - count(tracks.duration_ms) as "untimed!: i64"
+        from songs
+        left join tracks on tracks.id = songs.id
+        where strftime($1, songs.date) is not null
+            and ($2 is null or datetime(songs.date) >= datetime($2))
+            and ($3 is null or datetime(songs.date) < datetime($3))
+        group by 1
+        order by 1"#,
+        format,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ArtistTime {
+    pub id: String,
+    pub name: String,
+    pub listens: i64,
+    pub ms: i64,
+    pub minutes: f64,
+}
+
+/// the artists listened to longest. a track with two artists counts fully for
+/// both. only knows about listens since we started keeping artist ids
+pub async fn artist_time(
+    pool: &SqlitePool,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: u32,
+) -> Result<Vec<ArtistTime>, sqlx::Error> {
+    sqlx::query_as!(
+        ArtistTime,
+        r#"select
+            artists.id as "id!",
+            artists.name as "name!",
+            count(*) as "listens!: i64",
+            coalesce(sum(tracks.duration_ms), 0) as "ms!: i64",
+            round(coalesce(sum(tracks.duration_ms), 0) / 60000.0, 1) as "minutes!: f64"
+        from songs
+        join tracks on tracks.id = songs.id
+        join track_artists on track_artists.track_id = tracks.id
+        join artists on artists.id = track_artists.artist_id
+        where ($1 is null or datetime(songs.date) >= datetime($1))
+            and ($2 is null or datetime(songs.date) < datetime($2))
+        group by artists.id
+        order by 4 desc, 3 desc
+        limit $3"#,
+        from,
+        to,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TopAlbum {
+    pub name: String,
+    pub plays: i64,
+    pub ms: i64,
+}
+
+/// most played albums. ones with the same name are only told apart once we
+/// know their ids
+pub async fn top_albums_between(
+    pool: &SqlitePool,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: u32,
+) -> Result<Vec<TopAlbum>, sqlx::Error> {
+    sqlx::query_as!(
+        TopAlbum,
+        r#"select
+            songs.album as "name!",
+            count(*) as "plays!: i64",
+            coalesce(sum(tracks.duration_ms), 0) as "ms!: i64"
+        from songs
+        left join tracks on tracks.id = songs.id
+        where songs.album is not null
+            and ($1 is null or datetime(songs.date) >= datetime($1))
+            and ($2 is null or datetime(songs.date) < datetime($2))
+        group by coalesce(tracks.album_id, songs.album)
+        order by 2 desc, 3 desc
+        limit $3"#,
+        from,
+        to,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct NewArtist {
+    pub id: String,
+    pub name: String,
+    /// the first listen ever
+    pub first: String,
+}
+
+/// artists first listened to between `from` and `to`, in the order they were
+/// found. only knows about listens since we started keeping artist ids
+pub async fn new_artists(
+    pool: &SqlitePool,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<NewArtist>, sqlx::Error> {
+    sqlx::query_as!(
+        NewArtist,
+        r#"select
+            artists.id as "id!",
+            artists.name as "name!",
+            min(songs.date) as "first!: String"
+        from songs
+        join track_artists on track_artists.track_id = songs.id
+        join artists on artists.id = track_artists.artist_id
+        where songs.date is not null
+        group by artists.id
+        having ($1 is null or datetime(min(songs.date)) >= datetime($1))
+            and ($2 is null or datetime(min(songs.date)) < datetime($2))
+        order by 3"#,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// waiting to go out to `service`. goes in the same transaction as the listen
+pub async fn queue_outbox(
+    executor: impl SqliteExecutor<'_>,
+    service: &str,
+    date: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "insert or ignore into outbox (service, date) values ($1, $2)",
+        service,
+        date,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// the oldest listens for `service` that haven't gone out yet, weren't
+/// rejected, and aren't waiting to be retried
+pub async fn due_outbox(
+    pool: &SqlitePool,
+    service: &str,
+    limit: u32,
+) -> Result<Vec<SongRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        SongRecord,
+        "select songs.name, songs.album, songs.artist, songs.date, songs.id
+        from outbox join songs on songs.date = outbox.date
+        where outbox.service = $1
+            and outbox.submitted_at is null
+            and outbox.rejected_at is null
+            and (outbox.retry_at is null
+                or outbox.retry_at <= strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+        order by datetime(songs.date) asc
+        limit $2",
+        service,
+        limit,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+fn dates_json(listens: &[SongRecord]) -> String {
+    serde_json::to_string(
+        &listens
+            .iter()
+            .filter_map(|listen| listen.date.as_deref())
+            .collect::<Vec<_>>(),
+    )
+    .unwrap()
+}
+
+pub async fn outbox_sent(
+    pool: &SqlitePool,
+    service: &str,
+    listens: &[SongRecord],
+) -> Result<(), sqlx::Error> {
+    let dates = dates_json(listens);
+    sqlx::query!(
+        "update outbox
+        set submitted_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+            retry_at = null
+        where service = $1 and date in (select value from json_each($2))",
+        service,
+        dates,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// try again later, backing off from a minute to six hours
+pub async fn outbox_failed(
+    pool: &SqlitePool,
+    service: &str,
+    listens: &[SongRecord],
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    let dates = dates_json(listens);
+    sqlx::query!(
+        "update outbox
+        set attempts = attempts + 1,
+            last_error = $3,
+            retry_at = strftime(
+                '%Y-%m-%dT%H:%M:%fZ',
+                'now',
+                '+' || min(360, 1 << min(attempts, 9)) || ' minutes'
+            )
+        where service = $1 and date in (select value from json_each($2))",
+        service,
+        dates,
+        error,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// never going to be taken, so don't try again
+pub async fn outbox_rejected(
+    pool: &SqlitePool,
+    service: &str,
+    listens: &[SongRecord],
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    let dates = dates_json(listens);
+    sqlx::query!(
+        "update outbox
+        set attempts = attempts + 1,
+            last_error = $3,
+            rejected_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+            retry_at = null
+        where service = $1 and date in (select value from json_each($2))",
+        service,
+        dates,
+        error,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn load_global_auth(pool: &SqlitePool) -> Result<Option<TokenPair>, sqlx::Error> {
+    let Some(row) = sqlx::query!(
+        "select access_token, refresh_token, expires_at from global_auth where id = 1"
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    // we store when it expires, but everything else wants to know how long is left
+    let expires_in = row
+        .expires_at
+        .and_then(|at| DateTime::parse_from_rfc3339(&at).ok())
+        .map(|at| (at.with_timezone(&Utc) - Utc::now()).num_seconds().max(0) as u64);
+
+    Ok(Some(TokenPair {
+        access_token: row.access_token,
+        refresh_token: row.refresh_token,
+        expires_in,
+    }))
+}
+
+pub async fn save_global_auth(pool: &SqlitePool, tokens: &TokenPair) -> Result<(), sqlx::Error> {
+    let expires_at = tokens.expires_in.map(|secs| {
+        (Utc::now() + Duration::seconds(secs as i64)).to_rfc3339_opts(SecondsFormat::Secs, true)
+    });
+    sqlx::query!(
+        "insert into global_auth (id, access_token, refresh_token, expires_at)
+        values (1, $1, $2, $3)
+        on conflict (id) do update set
+            access_token = excluded.access_token,
+            refresh_token = excluded.refresh_token,
+            expires_at = excluded.expires_at",
+        tokens.access_token,
+        tokens.refresh_token,
+        expires_at,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// tracks we've got listens for but know nothing else about, from before
+/// spotti kept track of albums and artists or from an import
+pub async fn uncatalogued_tracks(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select distinct songs.id as "id!"
+        from songs left join tracks on tracks.id = songs.id
+        where songs.id is not null and songs.id != '' and tracks.id is null
+        order by songs.id"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_listens(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(r#"select count(*) as "count!: i64" from songs"#)
+        .fetch_one(pool)
+        .await
+}
@@ -0,0 +1,191 @@
+//! a page each for tracks, albums and artists: how much they've been played,
+//! since when, and every listen
+
+use crate::{
+    chart, db,
+    error::{wants_json, AppError},
+    escape,
+    server::{self, AppState, LOCAL_TIMES_SCRIPT},
+    SongRecord,
+};
+use axum::{
+    extract::{OriginalUri, Path, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::DateTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Track,
+    Album,
+    Artist,
+}
+
+pub async fn track(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    details(&state, &headers, uri, Kind::Track, &id).await
+}
+
+pub async fn album(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    details(&state, &headers, uri, Kind::Album, &id).await
+}
+
+pub async fn artist(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    details(&state, &headers, uri, Kind::Artist, &id).await
+}
+
+async fn details(
+    state: &AppState,
+    headers: &HeaderMap,
+    uri: OriginalUri,
+    kind: Kind,
+    id: &str,
+) -> Result<Response, AppError> {
+    let pool = &state.pool;
+    let (listens, name) = match kind {
+        Kind::Track => {
+            let listens = db::track_listens(pool, id)
+                .await
+                .map_err(AppError::database("track listens"))?;
+            let name = listens.first().and_then(|listen| listen.name.clone());
+            (listens, name)
+        }
+        Kind::Album => {
+            let listens = db::album_listens(pool, id)
+                .await
+                .map_err(AppError::database("album listens"))?;
+            let name = db::album_name(pool, id)
+                .await
+                .map_err(AppError::database("album name"))?;
+            (listens, name)
+        }
+        Kind::Artist => {
+            let listens = db::artist_listens(pool, id)
+                .await
+                .map_err(AppError::database("artist listens"))?;
+            let name = db::artist_name(pool, id)
+                .await
+                .map_err(AppError::database("artist name"))?;
+            (listens, name)
+        }
+    };
+    if listens.is_empty() && name.is_none() {
+        return Ok(server::not_found(State(state.clone()), uri).await);
+    }
+    let name = name.unwrap_or_default();
+
+    // most recent first
+    let last = listens.first().and_then(|listen| listen.date.clone());
+    let first = listens.last().and_then(|listen| listen.date.clone());
+    let months = chart::by_month(listens.iter().filter_map(day));
+
+    if wants_json(headers) {
+        let months = months
+            .iter()
+            .map(|(month, plays)| serde_json::json!({ "month": month, "plays": plays }))
+            .collect::<Vec<_>>();
+        return Ok(Json(serde_json::json!({
+            "kind": kind,
+            "id": id,
+            "name": name,
+            "plays": listens.len(),
+            "first": first,
+            "last": last,
+            "months": months,
+            "listens": listens,
+        }))
+        .into_response());
+    }
+
+    let config = &state.config;
+    let ids = listens
+        .iter()
+        .filter_map(|listen| listen.id.as_deref())
+        .collect::<Vec<_>>();
+    let links = db::track_links(pool, &ids)
+        .await
+        .map_err(AppError::database("track links"))?;
+
+    let mut page = format!(
+        r#"<!doctype html>
+<html>
+  <head><title>NOT LAST.FM: {}</title></head>
+  <style>
+table, td, th {{
+    border: 1px solid #090;
+    border-collapse: collapse;
+    padding-left: 4pt;
+    padding-right: 8pt;
+}}
+  </style>
+  <body>
+    <h1>{}</h1>
+"#,
+        escape(&name),
+        escape(&name),
+    );
+    page.push_str(&format!("    <p>{}</p>\n", config.get_new_link("back")));
+
+    // where a track's from
+    if kind == Kind::Track {
+        if let (Some(listen), Some(links)) = (listens.first(), links.get(id)) {
+            let album = listen.album.as_deref().unwrap_or("");
+            let artists = links
+                .artists
+                .iter()
+                .map(|(id, name)| config.artist_link(id, &escape(name)))
+                .collect::<Vec<_>>();
+            page.push_str(&format!(
+                "    <p>by {}, on {}</p>\n",
+                artists.join(", "),
+                match &links.album_id {
+                    Some(album_id) => config.album_link(album_id, &escape(album)),
+                    None => escape(album),
+                },
+            ));
+        }
+    }
+
+    page.push_str(&format!(
+        "    <p>{} {} {}</p>\n",
+        listens.len(),
+        if listens.len() == 1 { "play" } else { "plays" },
+        match (&first, &last) {
+            (Some(first), Some(last)) => format!(
+                "between <span class=datetime>{first}</span> and <span class=datetime>{last}</span>"
+            ),
+            _ => String::new(),
+        },
+    ));
+    if !months.is_empty() {
+        page.push_str(&chart::bars(&months));
+    }
+    // no play buttons, they need the main page's script
+    page.push_str(&server::make_table(config, &listens, &links, &None));
+    page.push_str(LOCAL_TIMES_SCRIPT);
+    page.push_str("  </body>\n</html>\n");
+
+    Ok(Html(page).into_response())
+}
+
+fn day(listen: &SongRecord) -> Option<chrono::NaiveDate> {
+    let date = DateTime::parse_from_rfc3339(listen.date.as_deref()?).ok()?;
+    Some(date.date_naive())
+}
@@ -0,0 +1,154 @@
+//! what `bot/downbot.py` used to do, minus the polling: answer a few commands
+//! and DM the owner when something breaks
+
+use crate::{db, error::AppError, health::format_uptime, server::AppState};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serenity::{
+    all::{Context, CreateMessage, EventHandler, GatewayIntents, Message, Ready, UserId},
+    async_trait,
+    http::Http,
+    Client,
+};
+
+const DEFAULT_TOP_DAYS: i64 = 7;
+const TOP_LIMIT: u32 = 10;
+
+struct Handler {
+    state: AppState,
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, _: Context, ready: Ready) {
+        tracing::info!("discord: logged in as {}", ready.user.name);
+    }
+
+    async fn message(&self, ctx: Context, message: Message) {
+        if message.author.bot {
+            return;
+        }
+
+        let reply = match reply(&self.state, &message.content).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return,
+            Err(err) => {
+                tracing::error!("discord {}: {err}", err.context());
+                String::from("spotti: something went wrong, check the logs")
+            }
+        };
+
+        if let Err(err) = message.channel_id.say(&ctx.http, reply).await {
+            tracing::warn!("couldn't reply on discord: {err}");
+        }
+    }
+}
+
+/// connect to the gateway and answer commands until the connection dies for good
+pub async fn run(state: AppState, token: String) {
+    let intents = GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::DIRECT_MESSAGES
+        | GatewayIntents::MESSAGE_CONTENT;
+
+    let client = Client::builder(&token, intents)
+        .event_handler(Handler { state })
+        .await;
+
+    let result = match client {
+        Ok(mut client) => client.start().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        tracing::error!("discord: {err}");
+    }
+}
+
+/// no gateway needed for this, just the rest api
+pub async fn direct_message(token: &str, owner: u64, text: &str) -> Result<(), serenity::Error> {
+    let http = Http::new(token);
+    UserId::new(owner)
+        .direct_message(
+            &http,
+            CreateMessage::new().content(format!("spotti: {text}")),
+        )
+        .await?;
+    Ok(())
+}
+
+/// what to say back to a message, if it was one of ours
+pub async fn reply(state: &AppState, content: &str) -> Result<Option<String>, AppError> {
+    let mut words = content.split_whitespace();
+    let reply = match words.next() {
+        Some("!uptime") => format!("spotti: {}", format_uptime(state.start_time.elapsed())),
+        Some("!np") => now_playing(state).await?,
+        Some("!top") => top(state, words.next()).await?,
+        _ => return Ok(None),
+    };
+    Ok(Some(reply))
+}
+
+async fn now_playing(state: &AppState) -> Result<String, AppError> {
+    let Some(listen) = db::last_listen(&state.pool)
+        .await
+        .map_err(AppError::database("discord np"))?
+    else {
+        return Ok(String::from("nothing yet"));
+    };
+
+    let mut reply = format!(
+        "**{}** by {}",
+        listen.name.as_deref().unwrap_or("?"),
+        listen.artist.as_deref().unwrap_or("?"),
+    );
+    if let Some(album) = &listen.album {
+        reply.push_str(&format!(" on *{album}*"));
+    }
+    // discord shows these as "5 minutes ago" in everyone's own timezone
+    if let Some(date) = listen
+        .date
+        .as_deref()
+        .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+    {
+        reply.push_str(&format!(" <t:{}:R>", date.timestamp()));
+    }
+    Ok(reply)
+}
+
+/// `!top` for the last week, `!top 30` for the last month, `!top all` for ever
+async fn top(state: &AppState, arg: Option<&str>) -> Result<String, AppError> {
+    let days = match arg {
+        Some("all") => None,
+        Some(days) => match days.parse::<i64>().ok().and_then(Duration::try_days) {
+            Some(days) if days > Duration::zero() => Some(days),
+            _ => return Ok(String::from("usage: !top [days|all]")),
+        },
+        None => Duration::try_days(DEFAULT_TOP_DAYS),
+    };
+    let (since, heading) = match days {
+        Some(days) => (
+            Utc::now()
+                .checked_sub_signed(days)
+                .map(|since| since.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            format!("last {} days", days.num_days()),
+        ),
+        None => (None, String::from("all time")),
+    };
+
+    let tracks = db::top_tracks(&state.pool, since.as_deref(), None, TOP_LIMIT)
+        .await
+        .map_err(AppError::database("discord top"))?;
+    if tracks.is_empty() {
+        return Ok(format!("nothing to show ({heading})"));
+    }
+
+    let mut reply = format!("top tracks, {heading}:");
+    for (i, track) in tracks.iter().enumerate() {
+        reply.push_str(&format!(
+            "\n{}. **{}** by {} ({})",
+            i + 1,
+            track.name,
+            track.artist,
+            track.plays
+        ));
+    }
+    Ok(reply)
+}
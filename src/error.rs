@@ -0,0 +1,392 @@
+use crate::{
+    alert::Alert,
+    db, escape,
+    server::{self, AppState},
+    Config, SpotifyError,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Json, Response},
+};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+/// how many individual errors the history page shows
+const HISTORY_LIMIT: u32 = 100;
+
+#[derive(Debug)]
+pub enum AppError {
+    /// nobody's logged in, or spotify didn't like the token we have
+    Auth {
+        context: &'static str,
+        cause: Option<SpotifyError>,
+    },
+
+    /// spotify is down or sent us something weird
+    Spotify {
+        context: &'static str,
+        cause: SpotifyError,
+    },
+
+    Database {
+        context: &'static str,
+        cause: sqlx::Error,
+    },
+
+    /// listenbrainz or last.fm didn't take what we sent
+    Forward {
+        context: &'static str,
+        message: String,
+    },
+
+    /// something in the config doesn't add up
+    Config {
+        context: &'static str,
+        message: String,
+    },
+
+    /// poisoned locks, broken sessions. shouldn't happen
+    Internal {
+        context: &'static str,
+        message: String,
+    },
+}
+
+impl AppError {
+    pub fn unauthorized(context: &'static str) -> AppError {
+        AppError::Auth {
+            context,
+            cause: None,
+        }
+    }
+
+    pub fn spotify(context: &'static str) -> impl FnOnce(SpotifyError) -> AppError {
+        move |cause| match cause {
+            cause if cause.is_unauthorized() => AppError::Auth {
+                context,
+                cause: Some(cause),
+            },
+
+            // we only get these when the configured spotify urls are bad
+            SpotifyError::Url(err) => AppError::Config {
+                context,
+                message: err.to_string(),
+            },
+
+            cause => AppError::Spotify { context, cause },
+        }
+    }
+
+    pub fn database(context: &'static str) -> impl FnOnce(sqlx::Error) -> AppError {
+        move |cause| AppError::Database { context, cause }
+    }
+
+    pub fn forward<E: std::fmt::Display>(context: &'static str) -> impl FnOnce(E) -> AppError {
+        move |err| AppError::Forward {
+            context,
+            message: err.to_string(),
+        }
+    }
+
+    pub fn internal<E: std::fmt::Display>(context: &'static str) -> impl FnOnce(E) -> AppError {
+        move |err| AppError::Internal {
+            context,
+            message: err.to_string(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Auth { .. } => StatusCode::UNAUTHORIZED,
+            AppError::Spotify { .. } | AppError::Forward { .. } => StatusCode::BAD_GATEWAY,
+            AppError::Database { .. } | AppError::Config { .. } | AppError::Internal { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Auth { .. } => "auth",
+            AppError::Spotify { .. } => "spotify",
+            AppError::Database { .. } => "database",
+            AppError::Forward { .. } => "forward",
+            AppError::Config { .. } => "config",
+            AppError::Internal { .. } => "internal",
+        }
+    }
+
+    /// what we were doing when it happened
+    pub fn context(&self) -> &'static str {
+        match self {
+            AppError::Auth { context, .. }
+            | AppError::Spotify { context, .. }
+            | AppError::Database { context, .. }
+            | AppError::Forward { context, .. }
+            | AppError::Config { context, .. }
+            | AppError::Internal { context, .. } => context,
+        }
+    }
+
+    /// whatever spotify sent back, if that's what went wrong
+    pub fn details(&self) -> Option<&str> {
+        match self {
+            AppError::Auth {
+                cause: Some(cause), ..
+            }
+            | AppError::Spotify { cause, .. } => cause.body(),
+            _ => None,
+        }
+    }
+
+    /// nobody needs to be woken up because someone hit refresh before logging in
+    pub fn should_report(&self) -> bool {
+        !matches!(self, AppError::Auth { cause: None, .. })
+    }
+
+    fn html(&self, config: &Config) -> Response {
+        let status = self.status();
+
+        if let AppError::Auth { cause: None, .. } = self {
+            return (
+                status,
+                Html(format!(
+                    r#"<!doctype html>
+<html>
+  <head><title>unauthorized</title></head>
+  <body>
+    <h1>you're unauthorized</h1>
+    <p>go get {}</p>
+  </body>
+</html>"#,
+                    config.authorize_link("authorized")
+                )),
+            )
+                .into_response();
+        }
+
+        (
+            status,
+            Html(format!(
+                r#"<!doctype html>
+<html>
+  <head><title>NOT LAST.FM: {}</title></head>
+  <body>
+    <h1>{} {}</h1>
+    <p>{}</p>
+    <pre><code>{}</code></pre>
+    <p>try {} or {}. if the problem persists tell zack</p>{}
+  </body>
+</html>"#,
+                status.as_u16(),
+                status.as_u16(),
+                status.canonical_reason().unwrap_or("").to_lowercase(),
+                self.context(),
+                escape(&self.to_string()),
+                config.authorize_link("authing"),
+                config.refresh_link("refreshing"),
+                if let Some(details) = self.details() {
+                    format!(
+                        r#"
+    <p>more info:</p>
+    <pre style=white-space:pre-wrap;><code>{}</code></pre>"#,
+                        escape(details)
+                    )
+                } else {
+                    String::new()
+                }
+            )),
+        )
+            .into_response()
+    }
+
+    fn json(&self) -> Response {
+        (
+            self.status(),
+            Json(serde_json::json!({
+                "error": self.kind(),
+                "status": self.status().as_u16(),
+                "context": self.context(),
+                "message": self.to_string(),
+                "details": self.details(),
+            })),
+        )
+            .into_response()
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Auth { cause: None, .. } => write!(f, "nobody is authorized"),
+            AppError::Auth {
+                cause: Some(cause), ..
+            } => write!(f, "{cause}"),
+            AppError::Spotify { cause, .. } => write!(f, "{cause}"),
+            AppError::Database { cause, .. } => write!(f, "{cause}"),
+            AppError::Forward { message, .. }
+            | AppError::Config { message, .. }
+            | AppError::Internal { message, .. } => {
+                write!(f, "{message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        // the real page gets rendered by render_errors, which knows about the
+        // config and what the client asked for
+        let mut response = (self.status(), self.to_string()).into_response();
+        response.extensions_mut().insert(Arc::new(self));
+        response
+    }
+}
+
+/// html for browsers, json for anyone who asks for it first
+pub(crate) fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+        .find(|media_type| matches!(*media_type, "text/html" | "application/json"))
+        == Some("application/json")
+}
+
+pub async fn render_errors(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let json = wants_json(request.headers());
+    let response = next.run(request).await;
+
+    let Some(err) = response.extensions().get::<Arc<AppError>>().cloned() else {
+        return response;
+    };
+
+    report(&state, &err).await;
+
+    if json {
+        err.json()
+    } else {
+        err.html(&state.config)
+    }
+}
+
+/// log it, keep it for the errors page, and alert about it in the background.
+/// for everything that goes wrong, not just in requests. await the handle to
+/// be sure the alert's gone out, like before exiting
+pub async fn report(state: &AppState, err: &AppError) -> Option<tokio::task::JoinHandle<bool>> {
+    if !err.should_report() {
+        tracing::debug!("{}: {err}", err.context());
+        return None;
+    }
+    tracing::error!("{}: {err} {:?}", err.context(), err.details());
+
+    let message = err.to_string();
+    if let Err(db_err) = db::record_error(&state.pool, err.context(), &message, err.details()).await
+    {
+        tracing::error!("couldn't record error: {db_err}");
+    }
+
+    let alert = Alert {
+        context: err.context().into(),
+        message,
+        details: err.details().map(String::from),
+    };
+    let alerter = state.alerter.clone();
+    Some(tokio::spawn(async move { alerter.alert(alert).await }))
+}
+
+/// everything that's gone wrong lately, for zack's eyes only
+pub async fn history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    session: Session,
+) -> Result<Response, AppError> {
+    if !server::is_owner(&session).await? {
+        return Err(AppError::unauthorized("errors"));
+    }
+
+    let counts = db::error_counts(&state.pool)
+        .await
+        .map_err(AppError::database("error counts"))?;
+    let recent = db::recent_errors(&state.pool, HISTORY_LIMIT)
+        .await
+        .map_err(AppError::database("recent errors"))?;
+
+    if wants_json(&headers) {
+        return Ok(Json(serde_json::json!({
+            "counts": counts,
+            "recent": recent,
+        }))
+        .into_response());
+    }
+
+    let mut page = String::from(
+        r#"<!doctype html>
+<html>
+  <head><title>NOT LAST.FM: errors</title></head>
+  <style>
+table, td, th {
+    border: 1px solid #090;
+    border-collapse: collapse;
+    padding-left: 4pt;
+    padding-right: 8pt;
+}
+  </style>
+  <body>
+    <h1>what's gone wrong</h1>
+"#,
+    );
+
+    page.push_str(&format!(
+        "    <p>{}</p>\n",
+        state.config.get_new_link("back")
+    ));
+
+    if counts.is_empty() {
+        page.push_str("    <p>nothing! nice</p>\n");
+    } else {
+        page.push_str(
+            "    <table><tr><th>count</th><th>context</th><th>message</th><th>first</th><th>last</th></tr>\n",
+        );
+        for count in &counts {
+            page.push_str(&format!(
+                "    <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                count.count,
+                escape(&count.context),
+                escape(&count.message),
+                count.first,
+                count.last,
+            ));
+        }
+        page.push_str("    </table>\n");
+
+        page.push_str("    <h2>recently</h2>\n");
+        for error in &recent {
+            page.push_str(&format!(
+                "    <p>{} <b>{}</b>: {}</p>\n",
+                error.at,
+                escape(&error.context),
+                escape(&error.message),
+            ));
+            if let Some(details) = &error.details {
+                page.push_str(&format!(
+                    "    <pre style=white-space:pre-wrap;><code>{}</code></pre>\n",
+                    escape(details)
+                ));
+            }
+        }
+    }
+
+    page.push_str("  </body>\n</html>");
+    Ok(Html(page).into_response())
+}
@@ -0,0 +1,290 @@
+//! getting listens out again, as a download or from the command line, without
+//! holding all of them in memory at once
+
+use crate::{db, server::AppState, SongRecord};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use futures_util::TryStreamExt;
+use sqlx::SqlitePool;
+use std::{io::Write, str::FromStr};
+use tokio::sync::mpsc;
+
+/// how much to gather up before handing it to the client
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// date, artist, album, name, id
+    Csv,
+    /// one listen per line, what `import` reads
+    #[default]
+    Json,
+    /// the `.scrobbler.log` format portable players write, which last.fm
+    /// uploaders and maloja can import
+    Scrobbler,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Json => "application/jsonl; charset=utf-8",
+            Format::Scrobbler => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn filename(self) -> &'static str {
+        match self {
+            Format::Csv => "spotti.csv",
+            Format::Json => "spotti.jsonl",
+            Format::Scrobbler => ".scrobbler.log",
+        }
+    }
+
+    fn header(self, out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Format::Csv => writeln!(out, "date,artist,album,name,id"),
+            Format::Json => Ok(()),
+            Format::Scrobbler => {
+                writeln!(out, "#AUDIOSCROBBLER/1.1")?;
+                writeln!(out, "#TZ/UTC")?;
+                writeln!(out, "#CLIENT/spotti {}", env!("CARGO_PKG_VERSION"))
+            }
+        }
+    }
+
+    /// false if there wasn't enough to go on to write it at all
+    fn write(self, out: &mut impl Write, listen: &SongRecord) -> std::io::Result<bool> {
+        let field = |field: &Option<String>| field.as_deref().unwrap_or("").to_owned();
+        match self {
+            Format::Csv => {
+                let fields = [
+                    &listen.date,
+                    &listen.artist,
+                    &listen.album,
+                    &listen.name,
+                    &listen.id,
+                ]
+                .map(|value| csv_field(&field(value)));
+                writeln!(out, "{}", fields.join(","))?;
+            }
+
+            Format::Json => {
+                serde_json::to_writer(&mut *out, listen)?;
+                writeln!(out)?;
+            }
+
+            Format::Scrobbler => {
+                // the timestamp is the one thing it can't do without
+                let Some(date) = listen
+                    .date
+                    .as_deref()
+                    .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+                else {
+                    return Ok(false);
+                };
+                let [artist, album, name] = [&listen.artist, &listen.album, &listen.name]
+                    .map(|value| field(value).replace(['\t', '\n'], " "));
+                // no track number, length, or musicbrainz id. L for listened
+                writeln!(
+                    out,
+                    "{artist}\t{album}\t{name}\t\t0\tL\t{}\t",
+                    date.timestamp()
+                )?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// a day, or a moment in rfc3339
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bound {
+    Day(NaiveDate),
+    Instant(DateTime<Utc>),
+}
+
+impl FromStr for Bound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(day) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Bound::Day(day));
+        }
+        DateTime::parse_from_rfc3339(s)
+            .map(|instant| Bound::Instant(instant.with_timezone(&Utc)))
+            .map_err(|_| format!("{s:?} isn't a date like 2024-01-31 or 2024-01-31T12:00:00Z"))
+    }
+}
+
+impl std::fmt::Display for Bound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bound::Day(day) => write!(f, "{day}"),
+            Bound::Instant(instant) => {
+                write!(f, "{}", instant.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Bound {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// which listens to export. `from` is inclusive, and so is `to` if it's a day,
+/// so `from=2024-01-01&to=2024-01-31` is all of january
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+pub struct Range {
+    pub from: Option<Bound>,
+    pub to: Option<Bound>,
+}
+
+impl Range {
+    /// in the format the database has them, `to` being exclusive
+    pub(crate) fn bounds(&self) -> (Option<String>, Option<String>) {
+        let format = |instant: DateTime<Utc>| instant.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let from = self.from.map(|from| match from {
+            Bound::Day(day) => day.and_time(Default::default()).and_utc(),
+            Bound::Instant(instant) => instant,
+        });
+        let to = self.to.and_then(|to| match to {
+            Bound::Day(day) => day
+                .succ_opt()
+                .map(|day| day.and_time(Default::default()).and_utc()),
+            Bound::Instant(instant) => Some(instant),
+        });
+        (from.map(format), to.map(format))
+    }
+}
+
+/// returns how many listens were written
+pub async fn write(
+    pool: &SqlitePool,
+    format: Format,
+    range: Range,
+    out: &mut impl Write,
+) -> Result<u64, ExportError> {
+    let (from, to) = range.bounds();
+    let mut listens = db::listens_between(pool, &from, &to);
+
+    format.header(out)?;
+    let mut written = 0;
+    while let Some(listen) = listens.try_next().await? {
+        if format.write(out, &listen)? {
+            written += 1;
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Database(sqlx::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::Database(err) => write!(f, "reading listens: {err}"),
+            ExportError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<sqlx::Error> for ExportError {
+    fn from(err: sqlx::Error) -> Self {
+        ExportError::Database(err)
+    }
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(err: std::io::Error) -> Self {
+        ExportError::Io(err)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DownloadQuery {
+    #[serde(default)]
+    format: Format,
+    #[serde(flatten)]
+    range: Range,
+}
+
+/// `?format=csv&from=2024-01-01&to=2024-01-31`. the rows go out as they come
+/// out of the database
+pub async fn download(
+    State(state): State<AppState>,
+    Query(query): Query<DownloadQuery>,
+) -> Response {
+    let (tx, mut rx) = mpsc::channel(4);
+
+    let format = query.format;
+    tokio::spawn(async move {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        format.header(&mut chunk).unwrap();
+
+        let (from, to) = query.range.bounds();
+        let mut listens = db::listens_between(&state.pool, &from, &to);
+        loop {
+            match listens.try_next().await {
+                Ok(Some(listen)) => {
+                    format.write(&mut chunk, &listen).unwrap();
+                    if chunk.len() < CHUNK_SIZE {
+                        continue;
+                    }
+                    let full = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_SIZE));
+                    // they stopped listening
+                    if tx.send(Ok(full)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                // too late for a proper error page, but cutting it off at
+                // least doesn't look like a complete download
+                Err(err) => {
+                    tracing::error!("export: {err}");
+                    let _ = tx.send(Err(std::io::Error::other(err))).await;
+                    return;
+                }
+            }
+        }
+        let _ = tx.send(Ok(chunk)).await;
+    });
+
+    let chunks = futures_util::stream::poll_fn(move |context| rx.poll_recv(context));
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", format.filename()),
+            ),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
@@ -0,0 +1,161 @@
+use crate::{db, error::AppError, server::AppState, SpotifyClient};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Mutex;
+
+/// don't let a slow spotify hold up the health check for too long
+const PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// anyone can hit the health check, so they don't get to make us hit spotify
+/// more often than this
+const PING_EVERY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// how long the ping took, or why it failed
+type Ping = Result<std::time::Duration, String>;
+
+/// things the health check wants to know that only happen in passing
+#[derive(Default)]
+pub struct Health {
+    last_ingest: Mutex<Option<DateTime<Utc>>>,
+    auth_expires: Mutex<Option<DateTime<Utc>>>,
+    /// held across the ping, so requests at the same time share one
+    last_ping: tokio::sync::Mutex<Option<(std::time::Instant, Ping)>>,
+}
+
+impl Health {
+    pub fn ingested(&self) {
+        *self.last_ingest.lock().unwrap() = Some(Utc::now());
+    }
+
+    pub fn last_ingest(&self) -> Option<DateTime<Utc>> {
+        *self.last_ingest.lock().unwrap()
+    }
+
+    /// spotify told us how long the global access token is good for
+    pub fn authorized(&self, expires_in: Option<u64>) {
+        *self.auth_expires.lock().unwrap() =
+            expires_in.map(|secs| Utc::now() + Duration::seconds(secs as i64));
+    }
+
+    pub fn auth_expires(&self) -> Option<DateTime<Utc>> {
+        *self.auth_expires.lock().unwrap()
+    }
+
+    /// the last ping, unless it's older than `PING_EVERY`
+    pub async fn ping(&self, spotify: &SpotifyClient) -> Ping {
+        let mut last_ping = self.last_ping.lock().await;
+        if let Some((at, ping)) = &*last_ping {
+            if at.elapsed() < PING_EVERY {
+                return ping.clone();
+            }
+        }
+
+        let start = std::time::Instant::now();
+        let ping = spotify
+            .ping(PING_TIMEOUT)
+            .await
+            .map(|()| start.elapsed())
+            .map_err(|err| err.to_string());
+        *last_ping = Some((std::time::Instant::now(), ping.clone()));
+        ping
+    }
+
+    /// if we've never ingested anything, count from when we started
+    pub fn since_ingest(&self, start_time: std::time::Instant) -> std::time::Duration {
+        match self.last_ingest() {
+            Some(last_ingest) => (Utc::now() - last_ingest).to_std().unwrap_or_default(),
+            None => start_time.elapsed(),
+        }
+    }
+}
+
+pub fn format_uptime(uptime: std::time::Duration) -> String {
+    // https://www.satsig.net/training/seconds-days-hours-minutes-calculator.htm
+    let totalseconds = uptime.as_secs();
+
+    let day = 86400;
+    let hour = 3600;
+    let minute = 60;
+
+    let daysout = totalseconds / day;
+    let hoursout = (totalseconds - daysout * day) / hour;
+    let minutesout = (totalseconds - daysout * day - hoursout * hour) / minute;
+    let secondsout = totalseconds - daysout * day - hoursout * hour - minutesout * minute;
+
+    format!("{daysout}d {hoursout}h {minutesout}m {secondsout}s")
+}
+
+fn db_size(db_file: &str) -> u64 {
+    // the wal can get pretty big between checkpoints
+    [String::from(db_file), format!("{db_file}-wal")]
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+pub async fn health(State(state): State<AppState>) -> Result<Response, AppError> {
+    let now = Utc::now();
+    let uptime = state.start_time.elapsed();
+
+    let global = state.global_auth.read().unwrap().is_some();
+    let expires_at = state.health.auth_expires().filter(|_| global);
+
+    let last_ingest = state.health.last_ingest();
+    let since_ingest = state.health.since_ingest(state.start_time);
+    let stale = since_ingest > state.config.stale_after;
+
+    let last_error = db::recent_errors(&state.pool, 1)
+        .await
+        .map_err(AppError::database("health last error"))?
+        .pop();
+    let listens = db::count_listens(&state.pool)
+        .await
+        .map_err(AppError::database("health count"))?;
+
+    let ping = state.health.ping(&state.spotify).await;
+
+    let status = if stale {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    Ok((
+        status,
+        Json(serde_json::json!({
+            "status": if stale { "stale" } else { "ok" },
+            "uptime": format_uptime(uptime),
+            "uptime_secs": uptime.as_secs(),
+            "auth": {
+                "global": global,
+                "expires_at": expires_at,
+                "expires_in_secs": expires_at.map(|at| (at - now).num_seconds()),
+            },
+            "ingest": {
+                "last_success": last_ingest,
+                "secs_since": since_ingest.as_secs(),
+                "stale_after_secs": state.config.stale_after.as_secs(),
+            },
+            // just what and when, the details are for the owner's error page
+            "last_error": last_error.map(|error| serde_json::json!({
+                "at": error.at,
+                "context": error.context,
+            })),
+            "database": {
+                "listens": listens,
+                "size_bytes": db_size(&state.config.db_file),
+            },
+            "spotify": {
+                "reachable": ping.is_ok(),
+                "latency_ms": ping.as_ref().ok().map(|latency| latency.as_millis() as u64),
+                "error": ping.err(),
+            },
+        })),
+    )
+        .into_response())
+}
@@ -0,0 +1,217 @@
+//! scrobbling to last.fm, for anyone who still wants a profile there
+
+use crate::{outbox::Forwarder, LastFmConfig, SongRecord};
+use chrono::DateTime;
+use md5::{Digest, Md5};
+use serde_json::Value;
+use url::Url;
+
+pub const LASTFM_API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// where people go to let us scrobble for them
+pub const LASTFM_AUTH_URL: &str = "https://www.last.fm/api/auth/";
+
+#[derive(Debug)]
+pub enum LastFmError {
+    Request(reqwest::Error),
+    Status {
+        status: u16,
+        body: String,
+    },
+    /// last.fm's own error codes, which can come with any status
+    Api {
+        code: i64,
+        message: String,
+    },
+    Json {
+        err: serde_json::Error,
+        body: String,
+    },
+}
+
+impl std::fmt::Display for LastFmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LastFmError::Request(err) => write!(f, "{err}"),
+            LastFmError::Status { status, body } => write!(f, "last.fm returned {status}: {body}"),
+            LastFmError::Api { code, message } => write!(f, "last.fm error {code}: {message}"),
+            LastFmError::Json { err, .. } => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for LastFmError {}
+
+impl From<reqwest::Error> for LastFmError {
+    fn from(err: reqwest::Error) -> Self {
+        LastFmError::Request(err)
+    }
+}
+
+pub struct LastFmClient {
+    http: reqwest::Client,
+    api_url: Url,
+    api_key: String,
+    api_secret: String,
+}
+
+impl LastFmClient {
+    pub fn new(http: reqwest::Client, config: &LastFmConfig) -> Self {
+        LastFmClient {
+            http,
+            api_url: config.api_url.clone(),
+            api_key: config.api_key.clone(),
+            api_secret: config.api_secret.clone(),
+        }
+    }
+
+    /// https://www.last.fm/api/authspec#_8-signing-calls
+    fn sign(&self, params: &[(String, String)]) -> String {
+        let mut sorted = params.iter().collect::<Vec<_>>();
+        sorted.sort();
+
+        let mut md5 = Md5::new();
+        for (name, value) in sorted {
+            md5.update(name);
+            md5.update(value);
+        }
+        md5.update(&self.api_secret);
+        format!("{:x}", md5.finalize())
+    }
+
+    async fn call(&self, method: &str, params: &[(String, String)]) -> Result<Value, LastFmError> {
+        let mut params = params.to_vec();
+        params.push(("method".into(), method.into()));
+        params.push(("api_key".into(), self.api_key.clone()));
+        let api_sig = self.sign(&params);
+        params.push(("api_sig".into(), api_sig));
+        // not part of the signature
+        params.push(("format".into(), "json".into()));
+
+        let response = self
+            .http
+            .post(self.api_url.clone())
+            .form(&params)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        let json = serde_json::from_str::<Value>(&body);
+        if let Ok(Value::Object(json)) = &json {
+            if let Some(code) = json.get("error").and_then(Value::as_i64) {
+                return Err(LastFmError::Api {
+                    code,
+                    message: json
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("")
+                        .to_owned(),
+                });
+            }
+        }
+        if !status.is_success() {
+            return Err(LastFmError::Status {
+                status: status.as_u16(),
+                body,
+            });
+        }
+        json.map_err(|err| LastFmError::Json { err, body })
+    }
+
+    /// the first step of giving us a session key
+    pub async fn get_token(&self) -> Result<String, LastFmError> {
+        let response = self.call("auth.getToken", &[]).await?;
+        string_at(&response, &["token"])
+    }
+
+    pub fn auth_url(&self, token: &str) -> Url {
+        let mut url = Url::parse(LASTFM_AUTH_URL).unwrap();
+        url.query_pairs_mut()
+            .append_pair("api_key", &self.api_key)
+            .append_pair("token", token);
+        url
+    }
+
+    /// once someone's allowed `token` at `auth_url`. session keys don't expire
+    pub async fn get_session(&self, token: &str) -> Result<String, LastFmError> {
+        let response = self
+            .call("auth.getSession", &[("token".into(), token.into())])
+            .await?;
+        string_at(&response, &["session", "key"])
+    }
+
+    /// returns how many last.fm ignored, usually for being too old
+    pub async fn scrobble(
+        &self,
+        session_key: &str,
+        listens: &[SongRecord],
+    ) -> Result<u64, LastFmError> {
+        let mut params = vec![("sk".to_owned(), session_key.to_owned())];
+        let listens = listens.iter().filter_map(|listen| {
+            let date = DateTime::parse_from_rfc3339(listen.date.as_deref()?).ok()?;
+            Some((listen, date.timestamp()))
+        });
+        for (i, (listen, timestamp)) in listens.enumerate() {
+            let mut param = |name: &str, value: &str| {
+                params.push((format!("{name}[{i}]"), value.to_owned()));
+            };
+            param("artist", listen.artist.as_deref().unwrap_or(""));
+            param("track", listen.name.as_deref().unwrap_or(""));
+            param("timestamp", &timestamp.to_string());
+            if let Some(album) = &listen.album {
+                param("album", album);
+            }
+        }
+
+        let response = self.call("track.scrobble", &params).await?;
+        // last.fm sends numbers as strings, but take either
+        let ignored = &response["scrobbles"]["@attr"]["ignored"];
+        Ok(ignored
+            .as_u64()
+            .or_else(|| ignored.as_str()?.parse().ok())
+            .unwrap_or(0))
+    }
+}
+
+fn string_at(json: &Value, path: &[&str]) -> Result<String, LastFmError> {
+    let mut value = json;
+    for key in path {
+        value = &value[key];
+    }
+    value
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| LastFmError::Json {
+            err: serde::de::Error::custom(format!("no {} in the response", path.join("."))),
+            body: json.to_string(),
+        })
+}
+
+/// a client and the session key to scrobble with
+pub struct Scrobbler {
+    pub client: LastFmClient,
+    pub session_key: String,
+}
+
+impl Forwarder for Scrobbler {
+    const SERVICE: &'static str = "lastfm";
+    /// the most track.scrobble takes at once
+    const BATCH_SIZE: u32 = 50;
+    type Error = LastFmError;
+
+    async fn submit(&self, listens: &[SongRecord]) -> Result<(), LastFmError> {
+        let ignored = self.client.scrobble(&self.session_key, listens).await?;
+        // nothing trying again would fix
+        if ignored > 0 {
+            tracing::warn!("last.fm ignored {ignored} scrobbles");
+        }
+        Ok(())
+    }
+
+    /// error 6 is something wrong with the parameters. the rest are about our
+    /// session or on their end
+    fn is_rejection(err: &LastFmError) -> bool {
+        matches!(err, LastFmError::Api { code: 6, .. })
+    }
+}
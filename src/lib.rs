@@ -1,11 +1,38 @@
-use std::{net::SocketAddr, path::PathBuf};
+use chrono::NaiveDate;
+use config::{ConfigError, ConfigProblem};
+use std::{collections::BTreeMap, convert::TryFrom, net::SocketAddr, path::PathBuf};
 use url::Url;
 
-pub const SPOTIFY_AUTH_URL: &'static str =
-    "https://accounts.spotify.com/authorize?response_type=code";
-pub const SPOTIFY_TOKEN_URL: &'static str = "https://accounts.spotify.com/api/token";
+pub mod alert;
+pub mod chart;
+pub mod cli;
+pub mod config;
+pub mod db;
+pub mod details;
+#[cfg(feature = "discord")]
+pub mod discord;
+pub mod error;
+pub mod export;
+pub mod health;
+pub mod lastfm;
+pub mod listenbrainz;
+pub mod metrics;
+pub mod outbox;
+pub mod search;
+pub mod server;
+pub mod spotify;
+pub mod stats;
+pub mod tls;
+pub mod wrapped;
+
+pub use spotify::{SpotifyClient, SpotifyError};
+
 pub const ME: &str = "th59jhhlgloqhkwcj5foha869";
 
+/// downbot checks in every few minutes, so an hour without new data is weird
+const DEFAULT_STALE_AFTER_SECS: u64 = 60 * 60;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct SongRecord {
     pub name: Option<String>,
     pub album: Option<String>,
@@ -23,8 +50,18 @@ pub struct Listens {
 pub struct Listen {
     pub played_at: String,
     pub track: Track,
+    /// the playlist, album or artist it was played from, if any
+    pub context: Option<Context>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Context {
+    pub r#type: String,
+    pub uri: String,
+    pub href: Option<String>,
 }
 
+/// everything after `id` can be missing, for local files especially
 #[derive(Debug, serde::Deserialize)]
 pub struct Track {
     pub album: Album,
@@ -32,6 +69,18 @@ pub struct Track {
     pub name: String,
     pub r#type: String,
     pub id: String,
+    pub duration_ms: Option<i64>,
+    pub explicit: Option<bool>,
+    pub popularity: Option<i64>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    #[serde(default)]
+    pub external_ids: ExternalIds,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ExternalIds {
+    pub isrc: Option<String>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -41,6 +90,19 @@ pub struct Album {
     pub name: String,
     pub r#type: String,
     pub id: String,
+    /// a year, a month or a day, depending on `release_date_precision`
+    pub release_date: Option<String>,
+    pub release_date_precision: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+}
+
+/// biggest first, spotify says
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Image {
+    pub url: String,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -58,12 +120,17 @@ pub struct Me {
 pub struct TokenPair {
     pub access_token: String,
     pub refresh_token: String,
+    /// seconds
+    #[serde(default)]
+    pub expires_in: Option<u64>,
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct MaybeAuth {
     pub access_token: String,
     pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -72,32 +139,168 @@ pub struct SessionAuth(pub TokenPair);
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct GlobalAuth(pub TokenPair);
 
+/// the bot answers `!uptime`, `!np` and `!top`, and DMs `owner` about errors.
+/// only does anything if spotti was built with the discord feature.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct DiscordConfig {
+    pub token: String,
+    pub owner: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StringListenBrainzConfig {
+    token: String,
+    api_url: Option<String>,
+}
+
+/// mirror every new listen to listenbrainz, with the user token from
+/// <https://listenbrainz.org/settings/>
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenBrainzConfig {
+    pub token: String,
+    /// somewhere other than listenbrainz.org, like a test stand-in
+    pub api_url: Url,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StringLastFmConfig {
+    api_key: String,
+    api_secret: String,
+    session_key: Option<String>,
+    api_url: Option<String>,
+}
+
+/// scrobble every new listen to last.fm. the key and secret are from
+/// <https://www.last.fm/api/account/create>, and `spotti lastfm-auth` gets the
+/// session key
+#[derive(Clone, Debug, PartialEq)]
+pub struct LastFmConfig {
+    pub api_key: String,
+    pub api_secret: String,
+    /// nothing gets scrobbled without one
+    pub session_key: Option<String>,
+    /// somewhere other than last.fm, like a test stand-in
+    pub api_url: Url,
+}
+
+/// serve https ourselves instead of behind a proxy. the key has to be
+/// PKCS#8, `BEGIN PRIVATE KEY`, which is what certbot writes these days.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// plain http here gets sent to `base_url`
+    pub redirect_address: Option<SocketAddr>,
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct StringConfig {
     db_file: String,
-    error_file: String,
-    bot_pidfile: String,
+    error_file: Option<String>,
+    bot_pidfile: Option<String>,
 
     client_id: String,
     client_secret: String,
 
     base_url: String,
+    /// everything is mounted under this, like `spotti` behind a reverse proxy
+    /// that sends `/spotti/` our way
+    #[serde(default)]
+    path_prefix: Option<String>,
     authorize_endpoint: String,
     refresh_endpoint: String,
     get_new_endpoint: String,
     show_all_endpoint: String,
     uptime_endpoint: String,
+    #[serde(default = "default_errors_endpoint")]
+    errors_endpoint: String,
+    #[serde(default = "default_health_endpoint")]
+    health_endpoint: String,
+    #[serde(default = "default_metrics_endpoint")]
+    metrics_endpoint: String,
+    #[serde(default = "default_export_endpoint")]
+    export_endpoint: String,
+    #[serde(default = "default_search_endpoint")]
+    search_endpoint: String,
+    #[serde(default = "default_track_endpoint")]
+    track_endpoint: String,
+    #[serde(default = "default_album_endpoint")]
+    album_endpoint: String,
+    #[serde(default = "default_artist_endpoint")]
+    artist_endpoint: String,
+    #[serde(default = "default_stats_endpoint")]
+    stats_endpoint: String,
+    #[serde(default = "default_wrapped_endpoint")]
+    wrapped_endpoint: String,
 
     get_new_limit: u32,
 
+    stale_after_secs: Option<u64>,
+
     address: String,
+
+    tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    spotify_api_url: Option<String>,
+    #[serde(default)]
+    spotify_accounts_url: Option<String>,
+
+    #[serde(default)]
+    alerts: Vec<alert::SinkConfig>,
+    alert_dedup_secs: Option<u64>,
+    alert_max_per_hour: Option<u32>,
+
+    discord: Option<DiscordConfig>,
+
+    listenbrainz: Option<StringListenBrainzConfig>,
+
+    lastfm: Option<StringLastFmConfig>,
+}
+
+fn default_errors_endpoint() -> String {
+    String::from("errors")
+}
+
+fn default_health_endpoint() -> String {
+    String::from("health")
+}
+
+fn default_metrics_endpoint() -> String {
+    String::from("metrics")
+}
+
+fn default_export_endpoint() -> String {
+    String::from("export")
+}
+
+fn default_search_endpoint() -> String {
+    String::from("search")
+}
+
+fn default_track_endpoint() -> String {
+    String::from("track")
+}
+
+fn default_album_endpoint() -> String {
+    String::from("album")
+}
+
+fn default_artist_endpoint() -> String {
+    String::from("artist")
+}
+
+fn default_stats_endpoint() -> String {
+    String::from("stats")
+}
+
+fn default_wrapped_endpoint() -> String {
+    String::from("wrapped")
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub db_file: String,
-    pub error_file: PathBuf,
-    pub bot_pidfile: PathBuf,
     pub client_id: String,
     pub client_secret: String,
 
@@ -106,102 +309,328 @@ pub struct Config {
     pub get_new_url: Url,
     pub show_all_url: Url,
     pub uptime_url: Url,
+    pub errors_url: Url,
+    pub health_url: Url,
+    pub metrics_url: Url,
+    pub export_url: Url,
+    pub search_url: Url,
+    /// these three have the spotify id on the end
+    pub track_url: Url,
+    pub album_url: Url,
+    pub artist_url: Url,
+    pub stats_url: Url,
+    pub wrapped_url: Url,
 
     pub get_new_limit: u32,
 
+    /// the health check complains if nothing's been ingested in this long
+    pub stale_after: std::time::Duration,
+
     pub address: SocketAddr,
+
+    pub tls: Option<TlsConfig>,
+
+    pub spotify_api_url: Url,
+    pub spotify_accounts_url: Url,
+
+    pub alerts: alert::AlertConfig,
+
+    pub discord: Option<DiscordConfig>,
+
+    pub listenbrainz: Option<ListenBrainzConfig>,
+
+    pub lastfm: Option<LastFmConfig>,
 }
 
 pub fn make_link(href: &str, text: &str) -> String {
     format!("<a href={href}>{text}</a>")
 }
 
+/// `url` with `id` as one more path segment
+fn with_id(url: &Url, id: &str) -> Url {
+    let mut url = url.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().push(id);
+    }
+    url
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 impl Config {
     pub fn authorize_link(&self, text: &str) -> String {
-        make_link(&self.authorize_url.as_str(), text)
+        make_link(self.authorize_url.as_str(), text)
     }
 
     pub fn refresh_link(&self, text: &str) -> String {
-        make_link(&self.refresh_url.as_str(), text)
+        make_link(self.refresh_url.as_str(), text)
     }
 
     pub fn get_new_link(&self, text: &str) -> String {
-        make_link(&self.get_new_url.as_str(), text)
+        make_link(self.get_new_url.as_str(), text)
     }
 
     pub fn show_all_link(&self, text: &str) -> String {
-        make_link(&self.show_all_url.as_str(), text)
+        make_link(self.show_all_url.as_str(), text)
     }
-}
 
-impl From<StringConfig> for Config {
-    fn from(config: StringConfig) -> Config {
-        let db_file = PathBuf::from(&config.db_file);
-        let error_file = PathBuf::from(&config.error_file);
-        let bot_pidfile = PathBuf::from(&config.bot_pidfile);
-        assert!(
-            db_file.exists(),
-            "db file {} does not exist",
-            db_file.display()
-        );
+    pub fn errors_link(&self, text: &str) -> String {
+        make_link(self.errors_url.as_str(), text)
+    }
 
-        let base_url = Url::parse(&config.base_url).expect("invalid base URL");
-        assert!(base_url.domain().is_some(), "need domain name");
+    pub fn track_link(&self, id: &str, text: &str) -> String {
+        make_link(with_id(&self.track_url, id).as_str(), text)
+    }
 
-        let mut authorize_url = base_url.clone();
-        if config.authorize_endpoint != "" {
-            authorize_url
-                .path_segments_mut()
-                .unwrap()
-                .push(&config.authorize_endpoint);
-        }
+    pub fn album_link(&self, id: &str, text: &str) -> String {
+        make_link(with_id(&self.album_url, id).as_str(), text)
+    }
 
-        let mut refresh_url = base_url.clone();
-        if config.refresh_endpoint != "" {
-            refresh_url
-                .path_segments_mut()
-                .unwrap()
-                .push(&config.refresh_endpoint);
+    pub fn artist_link(&self, id: &str, text: &str) -> String {
+        make_link(with_id(&self.artist_url, id).as_str(), text)
+    }
+
+    pub fn stats_link(&self, text: &str) -> String {
+        make_link(self.stats_url.as_str(), text)
+    }
+
+    /// the report for `from` to `to`, both days
+    pub fn wrapped_link(&self, from: NaiveDate, to: NaiveDate, text: &str) -> String {
+        let mut url = self.wrapped_url.clone();
+        url.query_pairs_mut()
+            .append_pair("from", &from.to_string())
+            .append_pair("to", &to.to_string());
+        make_link(url.as_str(), text)
+    }
+
+    pub fn spotify_client(&self, http: reqwest::Client) -> SpotifyClient {
+        SpotifyClient::new(
+            http,
+            &self.client_id,
+            &self.client_secret,
+            self.spotify_api_url.clone(),
+            self.spotify_accounts_url.clone(),
+        )
+    }
+}
+
+/// endpoints can have slashes in them, like `api/listens`. an empty one is the
+/// prefix itself, with a slash on the end
+fn endpoint_url(base_url: &Url, prefix: &str, endpoint: &str) -> Url {
+    let mut url = base_url.clone();
+    {
+        let mut path = url.path_segments_mut().expect("checked cannot_be_a_base");
+        path.pop_if_empty()
+            .extend(prefix.split('/').filter(|segment| !segment.is_empty()))
+            .extend(endpoint.split('/').filter(|segment| !segment.is_empty()));
+        if endpoint.split('/').all(str::is_empty) {
+            path.push("");
         }
+    }
+    url
+}
 
-        let mut get_new_url = base_url.clone();
-        if config.get_new_endpoint != "" {
-            get_new_url
-                .path_segments_mut()
-                .unwrap()
-                .push(&config.get_new_endpoint);
+/// `.` and `..` would get resolved away by browsers before they got to us
+fn check_path(key: &'static str, path: &str, problems: &mut Vec<ConfigProblem>) {
+    if path
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        problems.push(ConfigProblem::BadEndpoint {
+            key,
+            endpoint: path.into(),
+        });
+    }
+}
+
+fn parse_url(key: &'static str, url: &str, problems: &mut Vec<ConfigProblem>) -> Option<Url> {
+    match Url::parse(url) {
+        Ok(parsed) if parsed.cannot_be_a_base() => {
+            problems.push(ConfigProblem::CannotBeABase {
+                key,
+                url: url.into(),
+            });
+            None
+        }
+        Ok(parsed) => Some(parsed),
+        Err(err) => {
+            problems.push(ConfigProblem::BadUrl {
+                key,
+                url: url.into(),
+                err,
+            });
+            None
         }
+    }
+}
+
+impl TryFrom<StringConfig> for Config {
+    type Error = ConfigError;
+
+    /// checks everything it can before giving up, so the error has all of it
+    fn try_from(config: StringConfig) -> Result<Config, ConfigError> {
+        let mut problems = Vec::new();
 
-        let mut show_all_url = base_url.clone();
-        if config.show_all_endpoint != "" {
-            show_all_url
-                .path_segments_mut()
-                .unwrap()
-                .push(&config.show_all_endpoint);
+        let db_file = PathBuf::from(&config.db_file);
+        if !db_file.exists() {
+            problems.push(ConfigProblem::DbFileMissing(db_file));
         }
 
-        let mut uptime_url = base_url.clone();
-        if config.uptime_endpoint != "" {
-            uptime_url
-                .path_segments_mut()
-                .unwrap()
-                .push(&config.uptime_endpoint);
+        let base_url = parse_url("base_url", &config.base_url, &mut problems);
+        if base_url.as_ref().is_some_and(|url| url.domain().is_none()) {
+            problems.push(ConfigProblem::NoDomain(config.base_url.clone()));
+        }
+        if config.tls.is_some() && base_url.as_ref().is_some_and(|url| url.scheme() != "https") {
+            problems.push(ConfigProblem::TlsWithoutHttps(config.base_url.clone()));
+        }
+        // keep going with a stand-in so we can still look for collisions
+        let base_url = base_url.unwrap_or_else(|| Url::parse("http://spotti.invalid/").unwrap());
+
+        let prefix = config.path_prefix.as_deref().unwrap_or("");
+        check_path("path_prefix", prefix, &mut problems);
+
+        let endpoints = [
+            ("authorize_endpoint", &config.authorize_endpoint),
+            ("refresh_endpoint", &config.refresh_endpoint),
+            ("get_new_endpoint", &config.get_new_endpoint),
+            ("show_all_endpoint", &config.show_all_endpoint),
+            ("uptime_endpoint", &config.uptime_endpoint),
+            ("errors_endpoint", &config.errors_endpoint),
+            ("health_endpoint", &config.health_endpoint),
+            ("metrics_endpoint", &config.metrics_endpoint),
+            ("export_endpoint", &config.export_endpoint),
+            ("search_endpoint", &config.search_endpoint),
+            ("track_endpoint", &config.track_endpoint),
+            ("album_endpoint", &config.album_endpoint),
+            ("artist_endpoint", &config.artist_endpoint),
+            ("stats_endpoint", &config.stats_endpoint),
+            ("wrapped_endpoint", &config.wrapped_endpoint),
+        ]
+        .map(|(key, endpoint)| {
+            check_path(key, endpoint, &mut problems);
+            (key, endpoint_url(&base_url, prefix, endpoint))
+        });
+
+        let mut paths = BTreeMap::<&str, Vec<&'static str>>::new();
+        for (key, url) in &endpoints {
+            paths.entry(url.path()).or_default().push(key);
+        }
+        for (path, keys) in paths {
+            if keys.len() > 1 {
+                problems.push(ConfigProblem::DuplicateEndpoint {
+                    path: path.into(),
+                    keys,
+                });
+            }
         }
 
         let address = config
             .address
             .parse::<SocketAddr>()
-            .expect("invalid address");
-
-        tracing::info!("{}", authorize_url.as_str());
-        tracing::info!("{}", get_new_url.as_str());
-        tracing::info!("{}", refresh_url.as_str());
-        tracing::info!("{}", show_all_url.as_str());
-        tracing::info!("{}", uptime_url.as_str());
-        Config {
+            .map_err(|err| {
+                problems.push(ConfigProblem::BadAddress {
+                    address: config.address.clone(),
+                    err,
+                })
+            })
+            .ok();
+
+        if let Some(tls) = &config.tls {
+            for (key, path) in [("tls.cert", &tls.cert), ("tls.key", &tls.key)] {
+                if !path.exists() {
+                    problems.push(ConfigProblem::TlsFileMissing {
+                        key,
+                        path: path.clone(),
+                    });
+                }
+            }
+        }
+
+        let spotify_api_url = parse_url(
+            "spotify_api_url",
+            config
+                .spotify_api_url
+                .as_deref()
+                .unwrap_or(spotify::SPOTIFY_API_URL),
+            &mut problems,
+        );
+        let spotify_accounts_url = parse_url(
+            "spotify_accounts_url",
+            config
+                .spotify_accounts_url
+                .as_deref()
+                .unwrap_or(spotify::SPOTIFY_ACCOUNTS_URL),
+            &mut problems,
+        );
+
+        let listenbrainz = config.listenbrainz.map(|listenbrainz| {
+            let api_url = parse_url(
+                "listenbrainz.api_url",
+                listenbrainz
+                    .api_url
+                    .as_deref()
+                    .unwrap_or(listenbrainz::LISTENBRAINZ_API_URL),
+                &mut problems,
+            );
+            (listenbrainz.token, api_url)
+        });
+        let lastfm = config.lastfm.map(|lastfm| {
+            let api_url = parse_url(
+                "lastfm.api_url",
+                lastfm.api_url.as_deref().unwrap_or(lastfm::LASTFM_API_URL),
+                &mut problems,
+            );
+            (lastfm, api_url)
+        });
+
+        let mut sinks = Vec::new();
+        // what we did before there were sinks, for the discord bot
+        if let (Some(error_file), Some(bot_pidfile)) = (config.error_file, config.bot_pidfile) {
+            sinks.push(alert::Sink::Pidfile {
+                pidfile: PathBuf::from(bot_pidfile),
+                error_file: PathBuf::from(error_file),
+            });
+        }
+        match &config.discord {
+            #[cfg(feature = "discord")]
+            Some(discord) => sinks.push(alert::Sink::Discord {
+                token: discord.token.clone(),
+                owner: discord.owner,
+            }),
+            #[cfg(not(feature = "discord"))]
+            Some(_) => tracing::warn!("built without the discord feature, ignoring [discord]"),
+            None => {}
+        }
+        for (i, sink) in config.alerts.into_iter().enumerate() {
+            match alert::Sink::try_from(sink) {
+                Ok(sink) => sinks.push(sink),
+                Err(err) => problems.push(ConfigProblem::BadAlert { index: i + 1, err }),
+            }
+        }
+        let alerts =
+            alert::AlertConfig::new(sinks, config.alert_dedup_secs, config.alert_max_per_hour);
+
+        let (Some(address), Some(spotify_api_url), Some(spotify_accounts_url), true) = (
+            address,
+            spotify_api_url,
+            spotify_accounts_url,
+            problems.is_empty(),
+        ) else {
+            return Err(ConfigError { problems });
+        };
+
+        for (_, url) in &endpoints {
+            tracing::info!("{}", url.as_str());
+        }
+        let [authorize_url, refresh_url, get_new_url, show_all_url, uptime_url, errors_url, health_url, metrics_url, export_url, search_url, track_url, album_url, artist_url, stats_url, wrapped_url] =
+            endpoints.map(|(_, url)| url);
+
+        Ok(Config {
             db_file: config.db_file,
-            error_file,
-            bot_pidfile,
 
             client_id: config.client_id,
             client_secret: config.client_secret,
@@ -211,10 +640,46 @@ impl From<StringConfig> for Config {
             get_new_url,
             show_all_url,
             uptime_url,
+            errors_url,
+            health_url,
+            metrics_url,
+            export_url,
+            search_url,
+            track_url,
+            album_url,
+            artist_url,
+            stats_url,
+            wrapped_url,
 
             get_new_limit: config.get_new_limit,
 
+            stale_after: std::time::Duration::from_secs(
+                config.stale_after_secs.unwrap_or(DEFAULT_STALE_AFTER_SECS),
+            ),
+
             address,
-        }
+
+            tls: config.tls,
+
+            spotify_api_url,
+            spotify_accounts_url,
+
+            alerts,
+
+            discord: config.discord,
+
+            listenbrainz: listenbrainz.map(|(token, api_url)| ListenBrainzConfig {
+                token,
+                // any problems with it came up above
+                api_url: api_url.unwrap(),
+            }),
+
+            lastfm: lastfm.map(|(lastfm, api_url)| LastFmConfig {
+                api_key: lastfm.api_key,
+                api_secret: lastfm.api_secret,
+                session_key: lastfm.session_key,
+                api_url: api_url.unwrap(),
+            }),
+        })
     }
 }
@@ -0,0 +1,136 @@
+//! mirroring listens to listenbrainz
+
+use crate::{outbox::Forwarder, ListenBrainzConfig, SongRecord};
+use chrono::DateTime;
+use serde_json::{json, Value};
+use url::Url;
+
+pub const LISTENBRAINZ_API_URL: &str = "https://api.listenbrainz.org/";
+
+#[derive(Debug)]
+pub enum ListenBrainzError {
+    Url(url::ParseError),
+    Request(reqwest::Error),
+    Status { status: u16, body: String },
+}
+
+impl std::fmt::Display for ListenBrainzError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenBrainzError::Url(err) => write!(f, "{err}"),
+            ListenBrainzError::Request(err) => write!(f, "{err}"),
+            ListenBrainzError::Status { status, body } => {
+                write!(f, "listenbrainz returned {status}: {body}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ListenBrainzError {}
+
+impl From<url::ParseError> for ListenBrainzError {
+    fn from(err: url::ParseError) -> Self {
+        ListenBrainzError::Url(err)
+    }
+}
+
+impl From<reqwest::Error> for ListenBrainzError {
+    fn from(err: reqwest::Error) -> Self {
+        ListenBrainzError::Request(err)
+    }
+}
+
+pub struct ListenBrainzClient {
+    http: reqwest::Client,
+    api_url: Url,
+    token: String,
+}
+
+impl ListenBrainzClient {
+    pub fn new(http: reqwest::Client, config: &ListenBrainzConfig) -> Self {
+        ListenBrainzClient {
+            http,
+            api_url: config.api_url.clone(),
+            token: config.token.clone(),
+        }
+    }
+}
+
+/// https://listenbrainz.readthedocs.io/en/latest/users/json.html
+///
+/// none for a listen it would turn down, like one without an artist, so it
+/// doesn't take the rest of the batch with it
+fn payload(listen: &SongRecord) -> Option<Value> {
+    let listened_at = DateTime::parse_from_rfc3339(listen.date.as_deref()?).ok()?;
+    let artist = listen
+        .artist
+        .as_deref()
+        .filter(|artist| !artist.is_empty())?;
+    let name = listen.name.as_deref().filter(|name| !name.is_empty())?;
+
+    let mut additional_info = json!({
+        "submission_client": "spotti",
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+        "music_service": "spotify.com",
+    });
+    if let Some(id) = &listen.id {
+        additional_info["spotify_id"] = json!(format!("https://open.spotify.com/track/{id}"));
+    }
+
+    Some(json!({
+        "listened_at": listened_at.timestamp(),
+        "track_metadata": {
+            "artist_name": artist,
+            "track_name": name,
+            "release_name": listen.album,
+            "additional_info": additional_info,
+        },
+    }))
+}
+
+impl Forwarder for ListenBrainzClient {
+    const SERVICE: &'static str = "listenbrainz";
+    /// it takes up to 1000 at once, but a failure means trying the whole lot again
+    const BATCH_SIZE: u32 = 100;
+    type Error = ListenBrainzError;
+
+    async fn submit(&self, listens: &[SongRecord]) -> Result<(), ListenBrainzError> {
+        let payload = listens.iter().filter_map(payload).collect::<Vec<_>>();
+        if payload.is_empty() {
+            return Ok(());
+        }
+        let body = json!({
+            "listen_type": if payload.len() == 1 { "single" } else { "import" },
+            "payload": payload,
+        });
+
+        let response = self
+            .http
+            .post(self.api_url.join("1/submit-listens")?)
+            .header("Authorization", format!("Token {}", self.token))
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(ListenBrainzError::Status {
+                status: status.as_u16(),
+                body: response.text().await?,
+            })
+        }
+    }
+
+    /// a bad token or being rate limited is worth trying again, any other 4xx
+    /// is about what we sent
+    fn is_rejection(err: &ListenBrainzError) -> bool {
+        match err {
+            ListenBrainzError::Status { status, .. } => {
+                (400..500).contains(status) && !matches!(status, 401 | 403 | 429)
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{db, error::AppError, server::AppState};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+/// a counter with some labels, in the prometheus text format
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Counter {
+        Counter {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[&str], n: u64) {
+        debug_assert_eq!(labels.len(), self.labels.len(), "{}", self.name);
+        let labels = labels.iter().map(|label| String::from(*label)).collect();
+        *self.values.lock().unwrap().entry(labels).or_default() += n;
+    }
+
+    pub fn get(&self, labels: &[&str]) -> u64 {
+        let labels = labels
+            .iter()
+            .map(|label| String::from(*label))
+            .collect::<Vec<_>>();
+        self.values
+            .lock()
+            .unwrap()
+            .get(&labels)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn render(&self, out: &mut String) {
+        describe(out, self.name, self.help, "counter");
+        let values = self.values.lock().unwrap();
+        // unlabeled counters show up as zero before anything happens
+        if values.is_empty() && self.labels.is_empty() {
+            sample(out, self.name, &[], 0);
+        }
+        for (values, count) in values.iter() {
+            let labels = self
+                .labels
+                .iter()
+                .zip(values)
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect::<Vec<_>>();
+            sample(out, self.name, &labels, *count);
+        }
+    }
+}
+
+fn describe(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+            .collect::<Vec<_>>();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// everything we count. the gauges get worked out when someone asks
+#[derive(Debug)]
+pub struct Metrics {
+    pub ingest_runs: Counter,
+    pub rows_inserted: Counter,
+    pub spotify_requests: Counter,
+    pub token_refreshes: Counter,
+    pub http_requests: Counter,
+    pub forwarded: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            ingest_runs: Counter::new(
+                "spotti_ingest_runs_total",
+                "Times we asked spotify for recent listens and tried to store them.",
+                &["result"],
+            ),
+            rows_inserted: Counter::new(
+                "spotti_rows_inserted_total",
+                "Listens that weren't already in the database.",
+                &[],
+            ),
+            spotify_requests: Counter::new(
+                "spotti_spotify_requests_total",
+                "Requests to the spotify api, by endpoint and response status.",
+                &["endpoint", "status"],
+            ),
+            token_refreshes: Counter::new(
+                "spotti_token_refreshes_total",
+                "Attempts to refresh the global access token.",
+                &["result"],
+            ),
+            http_requests: Counter::new(
+                "spotti_http_requests_total",
+                "Requests we served, by route and response status.",
+                &["route", "status"],
+            ),
+            forwarded: Counter::new(
+                "spotti_forwarded_total",
+                "Listens sent on to other services, by service and result.",
+                &["service", "result"],
+            ),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn render(&self, out: &mut String) {
+        self.ingest_runs.render(out);
+        self.rows_inserted.render(out);
+        self.spotify_requests.render(out);
+        self.token_refreshes.render(out);
+        self.http_requests.render(out);
+        self.forwarded.render(out);
+    }
+}
+
+/// count every response by the route it matched, not the raw path, so random
+/// garbage doesn't make up a new series each time
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| String::from(path.as_str()))
+        .unwrap_or_else(|| String::from("unmatched"));
+
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .http_requests
+        .inc(&[&route, response.status().as_str()]);
+    response
+}
+
+pub async fn metrics(State(state): State<AppState>) -> Result<Response, AppError> {
+    let listens = db::count_listens(&state.pool)
+        .await
+        .map_err(AppError::database("metrics count"))?;
+
+    let since_poll = state.health.since_ingest(state.start_time);
+
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+
+    describe(
+        &mut out,
+        "spotti_listens",
+        "Listens in the database.",
+        "gauge",
+    );
+    sample(&mut out, "spotti_listens", &[], listens);
+
+    describe(
+        &mut out,
+        "spotti_seconds_since_last_poll",
+        "Seconds since we last got listens from spotify and stored them.",
+        "gauge",
+    );
+    sample(
+        &mut out,
+        "spotti_seconds_since_last_poll",
+        &[],
+        since_poll.as_secs_f64(),
+    );
+
+    describe(
+        &mut out,
+        "spotti_uptime_seconds",
+        "Seconds since spotti started.",
+        "gauge",
+    );
+    sample(
+        &mut out,
+        "spotti_uptime_seconds",
+        &[],
+        state.start_time.elapsed().as_secs_f64(),
+    );
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response())
+}
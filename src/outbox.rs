@@ -0,0 +1,171 @@
+//! listens on their way to other services. new ones get queued as they're
+//! ingested, in the same transaction, and go out from here, so an outage on
+//! their end just means they go out later
+
+use crate::{
+    db,
+    error::{self, AppError},
+    lastfm::{LastFmClient, Scrobbler},
+    listenbrainz::ListenBrainzClient,
+    server::AppState,
+    Config, SongRecord,
+};
+use sqlx::SqliteConnection;
+
+/// somewhere listens can go
+pub(crate) trait Forwarder {
+    /// what the outbox calls it
+    const SERVICE: &'static str;
+    const BATCH_SIZE: u32;
+    type Error: std::fmt::Display;
+
+    async fn submit(&self, listens: &[SongRecord]) -> Result<(), Self::Error>;
+
+    /// something in what we sent that it'll never take, rather than a problem
+    /// on their end or with our credentials
+    fn is_rejection(err: &Self::Error) -> bool;
+}
+
+/// the services the config says to send listens to
+fn services(config: &Config) -> Vec<&'static str> {
+    let mut services = Vec::new();
+    if config.listenbrainz.is_some() {
+        services.push(<ListenBrainzClient as Forwarder>::SERVICE);
+    }
+    if config
+        .lastfm
+        .as_ref()
+        .is_some_and(|lastfm| lastfm.session_key.is_some())
+    {
+        services.push(<Scrobbler as Forwarder>::SERVICE);
+    }
+    services
+}
+
+/// queue a new listen for everywhere it's going
+pub async fn queue(
+    connection: &mut SqliteConnection,
+    config: &Config,
+    date: &str,
+) -> Result<(), sqlx::Error> {
+    for service in services(config) {
+        db::queue_outbox(&mut *connection, service, date).await?;
+    }
+    Ok(())
+}
+
+/// what happened to one submission
+enum Outcome {
+    Sent,
+    /// given up on, but the rest can still go
+    Rejected,
+    /// try again later, along with everything after it
+    Failed,
+}
+
+/// writes down how `submit` went for `listens`
+async fn settle<F: Forwarder>(
+    state: &AppState,
+    listens: &[SongRecord],
+    result: Result<(), F::Error>,
+) -> Result<Outcome, sqlx::Error> {
+    let count = listens.len() as u64;
+    match result {
+        Ok(()) => {
+            state.metrics.forwarded.add(&[F::SERVICE, "ok"], count);
+            db::outbox_sent(&state.pool, F::SERVICE, listens).await?;
+            Ok(Outcome::Sent)
+        }
+        Err(err) => {
+            error::report(state, &AppError::forward(F::SERVICE)(&err)).await;
+            if F::is_rejection(&err) {
+                state
+                    .metrics
+                    .forwarded
+                    .add(&[F::SERVICE, "rejected"], count);
+                db::outbox_rejected(&state.pool, F::SERVICE, listens, &err.to_string()).await?;
+                Ok(Outcome::Rejected)
+            } else {
+                state.metrics.forwarded.add(&[F::SERVICE, "error"], count);
+                db::outbox_failed(&state.pool, F::SERVICE, listens, &err.to_string()).await?;
+                Ok(Outcome::Failed)
+            }
+        }
+    }
+}
+
+/// send everything that's due, a batch at a time, until it's all gone or
+/// something fails. returns how many went out
+async fn drain<F: Forwarder>(state: &AppState, forwarder: F) -> Result<u64, sqlx::Error> {
+    let mut sent = 0;
+    loop {
+        let listens = db::due_outbox(&state.pool, F::SERVICE, F::BATCH_SIZE).await?;
+        if listens.is_empty() {
+            return Ok(sent);
+        }
+
+        let result = forwarder.submit(&listens).await;
+        // one bad listen spoils the whole batch, so find it by going one at a
+        // time, and send the rest
+        if listens.len() > 1 && result.as_ref().is_err_and(F::is_rejection) {
+            for listen in listens.chunks(1) {
+                let result = forwarder.submit(listen).await;
+                match settle::<F>(state, listen, result).await? {
+                    Outcome::Sent => sent += 1,
+                    Outcome::Rejected => {}
+                    Outcome::Failed => return Ok(sent),
+                }
+            }
+            continue;
+        }
+
+        match settle::<F>(state, &listens, result).await? {
+            Outcome::Sent => sent += listens.len() as u64,
+            Outcome::Rejected => {}
+            Outcome::Failed => return Ok(sent),
+        }
+    }
+}
+
+/// everything that's due, everywhere. returns how many went out
+pub async fn forward(state: &AppState) -> Result<u64, sqlx::Error> {
+    let mut sent = 0;
+    if let Some(config) = &state.config.listenbrainz {
+        sent += drain(state, ListenBrainzClient::new(state.http.clone(), config)).await?;
+    }
+    if let Some(config) = &state.config.lastfm {
+        if let Some(session_key) = &config.session_key {
+            let scrobbler = Scrobbler {
+                client: LastFmClient::new(state.http.clone(), config),
+                session_key: session_key.clone(),
+            };
+            sent += drain(state, scrobbler).await?;
+        }
+    }
+    Ok(sent)
+}
+
+/// forward in the background, unless it's already happening or we're shutting
+/// down
+pub fn spawn_forward(state: &AppState) {
+    if services(&state.config).is_empty() {
+        return;
+    }
+    let Ok(ingesting) = state.ingesting.clone().try_read_owned() else {
+        return;
+    };
+    if *ingesting {
+        return;
+    }
+    let Ok(forwarding) = state.forwarding.clone().try_lock_owned() else {
+        return;
+    };
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = forward(&state).await {
+            error::report(&state, &AppError::database("outbox")(err)).await;
+        }
+        drop((forwarding, ingesting));
+    });
+}
@@ -0,0 +1,131 @@
+//! "when did I last play that", by track name, album or artist
+
+use crate::{
+    db,
+    error::{wants_json, AppError},
+    escape,
+    server::{AppState, LOCAL_TIMES_SCRIPT},
+    Config,
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+
+/// tracks, not listens
+const RESULT_LIMIT: u32 = 50;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+/// every word as a prefix, so `aphex twi` finds Aphex Twin. quoted, so
+/// nothing anyone types means anything special to fts5
+pub fn fts_query(q: &str) -> Option<String> {
+    let terms = q
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// the box at the top of the main page and the results
+pub(crate) fn search_form(config: &Config, q: &str) -> String {
+    format!(
+        "<form action={}><input type=search name=q value=\"{}\" placeholder=\"track, album or artist\"> <button>search</button></form>\n",
+        config.search_url.path(),
+        escape(q).replace('"', "&quot;"),
+    )
+}
+
+/// `?q=windowlicker`. each matching track, how many times it's been played,
+/// and when
+pub async fn search(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SearchQuery>,
+) -> Result<Response, AppError> {
+    let results = match fts_query(&query.q) {
+        Some(fts_query) => db::search(&state.pool, &fts_query, RESULT_LIMIT)
+            .await
+            .map_err(AppError::database("search"))?,
+        None => Vec::new(),
+    };
+
+    if wants_json(&headers) {
+        return Ok(Json(serde_json::json!({
+            "query": query.q,
+            "results": results,
+        }))
+        .into_response());
+    }
+
+    let mut page = String::from(
+        r#"<!doctype html>
+<html>
+  <head><title>NOT LAST.FM: search</title></head>
+  <style>
+table, td, th {
+    border: 1px solid #090;
+    border-collapse: collapse;
+    padding-left: 4pt;
+    padding-right: 8pt;
+}
+  </style>
+  <body>
+    <h1>when did zack last listen to that?</h1>
+"#,
+    );
+    page.push_str(&format!(
+        "    <p>{}</p>\n",
+        state.config.get_new_link("back")
+    ));
+    page.push_str(&search_form(&state.config, &query.q));
+
+    if results.is_empty() {
+        if !query.q.trim().is_empty() {
+            page.push_str("    <p>never, apparently</p>\n");
+        }
+    } else {
+        page.push_str(
+            "    <table><tr><th>title</th><th>album</th><th>artists</th><th>plays</th><th>last played</th><th>before that</th></tr>\n",
+        );
+        for result in &results {
+            let field = |field: &Option<String>| escape(field.as_deref().unwrap_or(""));
+            let Some((last, before)) = result.played_at.split_first() else {
+                continue;
+            };
+            let before = before
+                .iter()
+                .map(|date| format!("<div class=datetime>{date}</div>"))
+                .collect::<String>();
+            let before = if before.is_empty() {
+                before
+            } else {
+                format!(
+                    "<details><summary>{} more</summary>{before}</details>",
+                    result.plays - 1
+                )
+            };
+            page.push_str(&format!(
+                "    <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=datetime>{last}</td><td>{before}</td></tr>\n",
+                match &result.id {
+                    Some(id) => state.config.track_link(id, &field(&result.name)),
+                    None => field(&result.name),
+                },
+                field(&result.album),
+                field(&result.artist),
+                result.plays,
+            ));
+        }
+        page.push_str("    </table>\n");
+    }
+
+    page.push_str(LOCAL_TIMES_SCRIPT);
+    page.push_str("  </body>\n</html>\n");
+    Ok(Html(page).into_response())
+}
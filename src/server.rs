@@ -0,0 +1,792 @@
+use crate::{
+    alert::Alerter,
+    config::ConfigError,
+    db::{self, TrackLinks},
+    details,
+    error::{self, AppError},
+    escape, export,
+    health::{self, format_uptime, Health},
+    metrics::{self, Metrics},
+    outbox, search, stats, wrapped, Config, GlobalAuth, SessionAuth, SongRecord, SpotifyClient,
+    TokenPair,
+};
+use axum::{
+    extract::{self, Request, State},
+    http::StatusCode,
+    response::{self, Html, IntoResponse},
+    routing, Router,
+};
+use axum_extra::{headers::AccessControlAllowOrigin, TypedHeader};
+use rand::RngCore;
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+use tower::ServiceExt;
+use tower_sessions::{MemoryStore, Session};
+
+/// everything the handlers need. cheap to clone, and nothing in here is
+/// process-wide, so several can live side by side.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub pool: SqlitePool,
+    pub http: reqwest::Client,
+    pub spotify: SpotifyClient,
+    pub global_auth: Arc<RwLock<Option<GlobalAuth>>>,
+    pub alerter: Arc<Alerter>,
+    pub health: Arc<Health>,
+    pub metrics: Arc<Metrics>,
+    pub sessions: MemoryStore,
+    /// held for reading while listens are going into the database, and for
+    /// writing once we're shutting down. true once we have, so nothing starts
+    /// after
+    pub ingesting: Arc<tokio::sync::RwLock<bool>>,
+    /// one lot of forwarding at a time, so nothing gets sent twice
+    pub forwarding: Arc<tokio::sync::Mutex<()>>,
+    pub start_time: Instant,
+}
+
+impl AppState {
+    pub async fn new(config: Config) -> Result<AppState, sqlx::Error> {
+        let pool = crate::db::connect(&config.db_file).await?;
+        crate::db::migrate(&pool).await?;
+
+        let state = AppState::with_pool(config, pool);
+        if let Some(tokens) = crate::db::load_global_auth(&state.pool).await? {
+            tracing::info!("using saved global auth");
+            state.health.authorized(tokens.expires_in);
+            *state.global_auth.write().unwrap() = Some(GlobalAuth(tokens));
+        }
+        Ok(state)
+    }
+
+    pub fn with_pool(config: Config, pool: SqlitePool) -> AppState {
+        let http = reqwest::Client::new();
+        let metrics = Arc::new(Metrics::default());
+        AppState {
+            spotify: config
+                .spotify_client(http.clone())
+                .with_metrics(metrics.clone()),
+            alerter: Arc::new(Alerter::new(config.alerts.clone(), http.clone())),
+            config: Arc::new(config),
+            pool,
+            http,
+            global_auth: Arc::new(RwLock::new(None)),
+            health: Arc::new(Health::default()),
+            metrics,
+            sessions: MemoryStore::default(),
+            ingesting: Arc::new(tokio::sync::RwLock::new(false)),
+            forwarding: Arc::new(tokio::sync::Mutex::new(())),
+            start_time: Instant::now(),
+        }
+    }
+
+    /// waits for any ingestion that's still going, saves the global tokens
+    /// one more time, and closes the database. nothing gets ingested after.
+    pub async fn shutdown(&self) {
+        let mut shutting_down = self.ingesting.write().await;
+        *shutting_down = true;
+        drop(shutting_down);
+
+        let global_auth = self.global_auth.read().unwrap().clone();
+        if let Some(GlobalAuth(tokens)) = global_auth {
+            if let Err(err) = crate::db::save_global_auth(&self.pool, &tokens).await {
+                tracing::error!("couldn't save global auth: {err}");
+            }
+        }
+
+        self.pool.close().await;
+        tracing::info!("database closed");
+    }
+
+    /// the same app with a different config. tokens, sessions, the pool and
+    /// all the counters carry over. returns the names of what changed.
+    pub fn reload(&self, config: Config) -> Result<(AppState, Vec<&'static str>), ConfigError> {
+        let changes = self.config.reloadable_changes(&config)?;
+
+        // starting over would forget what's been deduped
+        let alerter = if config.alerts == self.config.alerts {
+            self.alerter.clone()
+        } else {
+            Arc::new(Alerter::new(config.alerts.clone(), self.http.clone()))
+        };
+
+        let state = AppState {
+            spotify: config
+                .spotify_client(self.http.clone())
+                .with_metrics(self.metrics.clone()),
+            alerter,
+            config: Arc::new(config),
+            ..self.clone()
+        };
+        Ok((state, changes))
+    }
+}
+
+/// a router that can be swapped for one built from a new config, without
+/// dropping the listener
+#[derive(Clone)]
+pub struct Reloadable {
+    current: Arc<RwLock<(AppState, Router)>>,
+}
+
+impl Reloadable {
+    pub fn new(state: AppState) -> Reloadable {
+        let router = router(state.clone());
+        Reloadable {
+            current: Arc::new(RwLock::new((state, router))),
+        }
+    }
+
+    pub fn state(&self) -> AppState {
+        self.current.read().unwrap().0.clone()
+    }
+
+    /// requests already in flight finish with the old config
+    pub fn reload(&self, config: Config) -> Result<Vec<&'static str>, ConfigError> {
+        let (state, changes) = self.state().reload(config)?;
+        let router = router(state.clone());
+        *self.current.write().unwrap() = (state, router);
+        Ok(changes)
+    }
+
+    /// hands every request to whichever router is current
+    pub fn router(&self) -> Router {
+        let current = self.current.clone();
+        Router::new().fallback(move |request: Request| {
+            let router = current.read().unwrap().1.clone();
+            async move { router.oneshot(request).await }
+        })
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    let mut secret = [0; 512];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let session_layer = tower_sessions::SessionManagerLayer::new(state.sessions.clone());
+
+    let config = &state.config;
+    Router::new()
+        .route(config.get_new_url.path(), routing::get(get_new))
+        .route(config.show_all_url.path(), routing::get(show_all))
+        .route(config.authorize_url.path(), routing::get(authorize))
+        .route(config.refresh_url.path(), routing::get(refresh))
+        .route(config.uptime_url.path(), routing::get(uptime))
+        .route(config.errors_url.path(), routing::get(error::history))
+        .route(config.health_url.path(), routing::get(health::health))
+        .route(config.metrics_url.path(), routing::get(metrics::metrics))
+        .route(config.export_url.path(), routing::get(export::download))
+        .route(config.search_url.path(), routing::get(search::search))
+        .route(
+            &format!("{}/:id", config.track_url.path()),
+            routing::get(details::track),
+        )
+        .route(
+            &format!("{}/:id", config.album_url.path()),
+            routing::get(details::album),
+        )
+        .route(
+            &format!("{}/:id", config.artist_url.path()),
+            routing::get(details::artist),
+        )
+        .route(config.stats_url.path(), routing::get(stats::page))
+        .route(config.wrapped_url.path(), routing::get(wrapped::page))
+        .fallback(not_found)
+        .layer(session_layer)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            error::render_errors,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_requests,
+        ))
+        .with_state(state)
+}
+
+pub(crate) async fn not_found(
+    State(state): State<AppState>,
+    extract::OriginalUri(path): extract::OriginalUri,
+) -> response::Response {
+    let config = &state.config;
+    (
+        StatusCode::NOT_FOUND,
+        Html(format!(
+            r#"<!doctype html>
+<html>
+  <head><title>uhhhh</title></head>
+  <body>
+    <h1>uhhhh</h1>
+    <p>you DEFINITELY shouldn't be able to see this</p>
+    <p>{}</p>
+    <p>you requested "{}"</p>
+  </body>
+</html>"#,
+            config.get_new_link("try this?"),
+            path
+        )),
+    )
+        .into_response()
+}
+
+const PAGE_HEADER: &str = r#"
+<!doctype html>
+<head><title>NOT LAST.FM</title></head>
+<style>
+table, td, th {
+    border: 1px solid #090;
+    border-collapse: collapse;
+    padding-left: 4pt;
+    padding-right: 8pt;
+}
+.datetime {
+    width: 20%;
+}
+</style>
+<body>
+<h1>what's zack been listening to recently?</h1>
+"#;
+
+const PAGE_FOOTER: &str = "</body></html>";
+
+/// shows anything with class=datetime in the reader's own time zone
+pub(crate) const LOCAL_TIMES_SCRIPT: &str = r#"
+<script type=text/javascript>
+for (el of document.getElementsByClassName('datetime')) {
+    let date = new Date(el.innerText);
+    if (!isNaN(date.getYear())) {
+        el.textContent = date.toLocaleDateString(
+            'en-us', {
+                year: 'numeric',
+                month: 'short',
+                day: 'numeric',
+                hour: 'numeric',
+                minute: 'numeric',
+                second: 'numeric',
+            }
+        );
+    }
+}
+</script>
+"#;
+
+async fn get_new(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<response::Response, AppError> {
+    let limit = state.config.get_new_limit;
+    do_db_stuff(&state, session, Some(limit)).await
+}
+
+async fn show_all(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<response::Response, AppError> {
+    do_db_stuff(&state, session, None).await
+}
+
+async fn authorize(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<std::collections::HashMap<String, String>>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    mut session: Session,
+) -> Result<response::Response, AppError> {
+    if let Some(code) = query.get("code") {
+        tracing::debug!("{addr} got code, doing oauth2");
+        tracing::trace!("code={}", code);
+        return do_oauth2(&state, code, &mut session).await;
+    }
+
+    let config = &state.config;
+    let spotify_auth_redirect = state
+        .spotify
+        .authorize_url(&config.authorize_url)
+        .map_err(AppError::spotify("spotify_auth_redirect malformed"))?;
+
+    tracing::debug!("{addr} redirecting to {}", spotify_auth_redirect.as_str());
+    Ok(response::Redirect::to(spotify_auth_redirect.as_str()).into_response())
+}
+
+async fn refresh(State(state): State<AppState>) -> Result<response::Response, AppError> {
+    refresh_global(&state).await?;
+
+    Ok(Html(format!(
+        r#"<!doctype html>
+<html>
+  <head><title>NOT LAST.FM: refreshed</title></head>
+  <body>
+    <h1>ahhhhh</h1>
+    <p>refreshing. {}</p>
+  </body>
+</html>"#,
+        state.config.get_new_link("back")
+    ))
+    .into_response())
+}
+
+/// trade the global refresh token for a new access token, and remember it
+pub async fn refresh_global(state: &AppState) -> Result<(), AppError> {
+    let config = &state.config;
+    let refresh_token = {
+        let Some(auth) = &*state
+            .global_auth
+            .read()
+            .map_err(AppError::internal("lock global auth refresh read"))?
+        else {
+            return Err(AppError::unauthorized("refresh"));
+        };
+
+        auth.0.refresh_token.clone()
+    };
+
+    let maybe_auth = state
+        .spotify
+        .refresh_token(&refresh_token, &config.authorize_url)
+        .await;
+    state
+        .metrics
+        .token_refreshes
+        .inc(&[if maybe_auth.is_ok() { "ok" } else { "error" }]);
+    let maybe_auth = maybe_auth.map_err(AppError::spotify("refresh"))?;
+
+    let tokens = TokenPair {
+        access_token: maybe_auth.access_token,
+        refresh_token: maybe_auth.refresh_token.unwrap_or(refresh_token),
+        expires_in: maybe_auth.expires_in,
+    };
+    set_global_auth(state, tokens).await
+}
+
+/// use these tokens for ingesting from now on, including after a restart
+pub async fn set_global_auth(state: &AppState, tokens: TokenPair) -> Result<(), AppError> {
+    crate::db::save_global_auth(&state.pool, &tokens)
+        .await
+        .map_err(AppError::database("save global auth"))?;
+
+    state.health.authorized(tokens.expires_in);
+    *state
+        .global_auth
+        .write()
+        .map_err(AppError::internal("lock global auth write"))? = Some(GlobalAuth(tokens));
+    Ok(())
+}
+
+/// refresh the global token and ingest whatever's new, without anyone having to
+/// visit a page. returns how many listens we hadn't seen before.
+pub async fn poll_once(state: &AppState) -> Result<u64, AppError> {
+    // not worth a new token if it won't get used
+    if *state.ingesting.read().await {
+        return Err(shutting_down());
+    }
+    refresh_global(state).await?;
+
+    let global_auth = state
+        .global_auth
+        .read()
+        .map_err(AppError::internal("lock global auth poll"))?
+        .clone()
+        .ok_or_else(|| AppError::unauthorized("poll"))?;
+    write_to_db(state, &global_auth).await
+}
+
+async fn uptime(State(state): State<AppState>) -> Result<response::Response, AppError> {
+    let uptime = Instant::now() - state.start_time;
+    Ok(format_uptime(uptime).into_response())
+}
+
+async fn do_db_stuff(
+    state: &AppState,
+    session: Session,
+    limit: Option<u32>,
+) -> Result<response::Response, AppError> {
+    let config = &state.config;
+    let global_auth = {
+        let guard = state.global_auth.read().unwrap();
+        guard.clone()
+    };
+
+    let session_auth = session
+        .get::<SessionAuth>("auth")
+        .await
+        .map_err(AppError::internal("get auth"))?;
+    let global_auth_available = global_auth.is_some();
+    if let Some(global_auth) = &global_auth {
+        write_to_db(state, global_auth).await?;
+    }
+
+    let mut page = String::from(PAGE_HEADER);
+    page.push_str(&search::search_form(config, ""));
+    let results = read_from_db(state, limit).await?;
+
+    if !global_auth_available {
+        page.push_str("<p><em>");
+        page.push_str(&format!(
+            "global auth was not available, this list may not be up to date. please tell zack. {} or {}?",
+            config.authorize_link("authorize"),
+            config.refresh_link("refresh")
+        ));
+        page.push_str("</em></p>");
+    }
+
+    if limit.is_none() {
+        page.push_str("<p>");
+        page.push_str(&config.get_new_link("back"));
+        page.push_str("</p>");
+    }
+
+    if session_auth.is_none() {
+        page.push_str(&format!(
+            "<p>{} to listen in (requres spotify premium and clears your queue)</p>",
+            config.authorize_link("log in")
+        ));
+    }
+
+    let ids = results
+        .iter()
+        .filter_map(|result| result.id.as_deref())
+        .collect::<Vec<_>>();
+    let links = db::track_links(&state.pool, &ids)
+        .await
+        .map_err(AppError::database("track links"))?;
+    page.push_str(&make_table(config, &results, &links, &session_auth));
+
+    if let Some(session_auth) = session_auth.as_ref() {
+        page.push_str(
+            r#"
+<script type=text/javascript>
+
+function addToQueue(id) {
+    let req = {
+        'mode': 'cors',
+        'method': 'PUT',
+        'headers': {
+            'Authorization': 'Bearer "#,
+        );
+        page.push_str(&session_auth.0.access_token);
+        page.push_str(
+            r#"'
+        },
+        'body': JSON.stringify({
+            'uris': [id],
+        }),
+    };
+
+    fetch('https://api.spotify.com/v1/me/player/play', req)
+        .then((response) => console.log(response))
+}
+
+for (el of document.getElementsByClassName('add')) {
+    const id = el.id;
+    el.addEventListener('click', function() {
+        console.log('click on ' + id);
+        addToQueue(id);
+    });
+}
+
+</script>
+    "#,
+        );
+    }
+
+    if limit.is_some() {
+        page.push_str("<p><em>");
+        page.push_str(&config.show_all_link("show all"));
+        page.push_str("</em></p>");
+    }
+    page.push_str("<p><em>");
+    page.push_str(&config.stats_link("how long?"));
+    page.push_str("</em></p>");
+
+    page.push_str(LOCAL_TIMES_SCRIPT);
+
+    page.push_str(PAGE_FOOTER);
+
+    Ok((
+        if global_auth_available {
+            StatusCode::OK
+        } else {
+            StatusCode::ACCEPTED
+        },
+        TypedHeader(AccessControlAllowOrigin::ANY),
+        Html(page),
+    )
+        .into_response())
+}
+
+async fn write_to_db(state: &AppState, auth: &GlobalAuth) -> Result<u64, AppError> {
+    // in a task of its own, so it finishes even if whoever asked for it hangs
+    // up, and shutting down can wait for it
+    let ingesting = state.ingesting.clone().read_owned().await;
+    if *ingesting {
+        return Err(shutting_down());
+    }
+    let (state, auth) = (state.clone(), auth.clone());
+    tokio::spawn(async move {
+        let result = ingest(&state, &auth).await;
+        // anything that failed to go out last time gets another go too
+        if result.is_ok() {
+            outbox::spawn_forward(&state);
+        }
+        state
+            .metrics
+            .ingest_runs
+            .inc(&[if result.is_ok() { "ok" } else { "error" }]);
+        drop(ingesting);
+        result
+    })
+    .await
+    .map_err(AppError::internal("ingest task"))?
+}
+
+fn shutting_down() -> AppError {
+    AppError::internal("ingest")("shutting down")
+}
+
+async fn ingest(state: &AppState, auth: &GlobalAuth) -> Result<u64, AppError> {
+    let listens = state
+        .spotify
+        .recently_played(&auth.0.access_token, 50)
+        .await
+        .map_err(AppError::spotify("recently-played"))?;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(AppError::database("start xact"))?;
+
+    let mut inserted = 0;
+    for listen in listens.items {
+        let mut artist = String::new();
+        for (i, a) in listen.track.artists.iter().enumerate() {
+            artist.push_str(&a.name);
+            if i + 1 != listen.track.artists.len() {
+                artist.push_str(", ");
+            }
+        }
+
+        let record = SongRecord {
+            name: Some(listen.track.name.clone()),
+            album: Some(listen.track.album.name.clone()),
+            artist: Some(artist),
+            date: Some(listen.played_at.clone()),
+            id: Some(listen.track.id.clone()),
+        };
+        if crate::db::insert_listen(&mut *tx, &record)
+            .await
+            .map_err(AppError::database("db insert"))?
+        {
+            inserted += 1;
+            db::save_track(&mut tx, &listen.track)
+                .await
+                .map_err(AppError::database("save track"))?;
+            if let Some(context) = &listen.context {
+                db::save_context(&mut *tx, &listen.played_at, context)
+                    .await
+                    .map_err(AppError::database("save context"))?;
+            }
+            outbox::queue(&mut tx, &state.config, &listen.played_at)
+                .await
+                .map_err(AppError::database("queue outbox"))?;
+        }
+    }
+
+    tx.commit()
+        .await
+        .map_err(AppError::database("xact commit"))?;
+    state.metrics.rows_inserted.add(&[], inserted);
+    state.health.ingested();
+
+    Ok(inserted)
+}
+
+async fn read_from_db(state: &AppState, limit: Option<u32>) -> Result<Vec<SongRecord>, AppError> {
+    if let Some(limit) = limit {
+        sqlx::query_as!(
+            SongRecord,
+            "select * from songs order by datetime(date) desc limit $1",
+            limit
+        )
+        .fetch_all(&state.pool)
+        .await
+        .map_err(AppError::database("sql error"))
+    } else {
+        sqlx::query_as!(
+            SongRecord,
+            "select * from songs order by datetime(date) desc"
+        )
+        .fetch_all(&state.pool)
+        .await
+        .map_err(AppError::database("sql error"))
+    }
+}
+
+// classic function name
+pub(crate) fn make_table(
+    config: &Config,
+    results: &[SongRecord],
+    links: &HashMap<String, TrackLinks>,
+    session_auth: &Option<SessionAuth>,
+) -> String {
+    let mut table = String::new();
+
+    table.push_str(
+        r#"
+<table><tr>
+<th><b>play</b></th>
+<th><b>title</b></th>
+<th><b>album</b></th>
+<th><b>artists</b></th>
+<th><b>time</b></th>
+<th><b>id</b></th>
+</tr>
+"#,
+    );
+
+    for result in results {
+        table.push_str("<tr>");
+
+        if session_auth.is_some() {
+            table.push_str("<td ");
+            if let Some(id) = result.id.as_ref() {
+                table.push_str(&format!(
+                    "style='cursor:pointer;' class='add' id='spotify:track:{}'>▶️</td>",
+                    id
+                ));
+            } else {
+                table.push_str("></td>");
+            }
+        } else {
+            table.push_str("<td>");
+            table.push_str("</td>");
+        }
+
+        let track_links = result.id.as_ref().and_then(|id| links.get(id));
+
+        table.push_str("<td>");
+        if let Some(name) = result.name.as_ref() {
+            match result.id.as_ref() {
+                Some(id) => table.push_str(&config.track_link(id, &escape(name))),
+                None => table.push_str(&escape(name)),
+            }
+        }
+        table.push_str("</td>");
+        table.push_str("<td>");
+        if let Some(album) = result.album.as_ref() {
+            match track_links.and_then(|links| links.album_id.as_ref()) {
+                Some(album_id) => table.push_str(&config.album_link(album_id, &escape(album))),
+                None => table.push_str(&escape(album)),
+            }
+        }
+        table.push_str("</td>");
+        table.push_str("<td>");
+        match track_links.filter(|links| !links.artists.is_empty()) {
+            Some(links) => {
+                let artists = links
+                    .artists
+                    .iter()
+                    .map(|(id, name)| config.artist_link(id, &escape(name)))
+                    .collect::<Vec<_>>();
+                table.push_str(&artists.join(", "));
+            }
+            None => {
+                if let Some(artist) = result.artist.as_ref() {
+                    table.push_str(&escape(artist));
+                }
+            }
+        }
+        table.push_str("</td>");
+        table.push_str("<td class='datetime'>");
+        if let Some(date) = result.date.as_ref() {
+            table.push_str(date);
+        }
+        table.push_str("</td>");
+        table.push_str("<td>");
+        if let Some(id) = result.id.as_ref() {
+            table.push_str(id);
+        }
+        table.push_str("</td>");
+
+        table.push_str("</tr>\n");
+    }
+
+    table.push_str("</table>");
+
+    table
+}
+
+async fn do_oauth2(
+    state: &AppState,
+    code: &str,
+    session: &mut Session,
+) -> Result<response::Response, AppError> {
+    let config = &state.config;
+    let tokens = state
+        .spotify
+        .request_token(code, &config.authorize_url)
+        .await
+        .map_err(AppError::spotify("token"))?;
+
+    let was_me = was_me(state, &tokens).await?;
+    if was_me {
+        let have_global_auth = state
+            .global_auth
+            .read()
+            .map_err(AppError::internal("lock for reading (authorize)"))?
+            .is_some();
+        if !have_global_auth {
+            tracing::info!("deviously stealing credentials");
+            set_global_auth(state, tokens.clone()).await?;
+        }
+    }
+
+    tracing::trace!("tokens={:?}", tokens);
+    session
+        .insert("auth", SessionAuth(tokens))
+        .await
+        .map_err(AppError::internal("token session"))?;
+    session
+        .insert("owner", was_me)
+        .await
+        .map_err(AppError::internal("owner session"))?;
+
+    Ok(Html(format!(
+        r#"<!doctype html>
+  <head><title>NOT LAST.FM: authorized</title></head>
+  <body>
+    <h1>nice! you're authorized</h1>
+    <p><em>{}</em></p>
+    <p>{}</p>
+  </body>
+</html>"#,
+        if was_me {
+            "and very handsome at that"
+        } else {
+            "not globally though :/"
+        },
+        config.get_new_link("back"),
+    ))
+    .into_response())
+}
+
+/// whether this session logged in as zack
+pub(crate) async fn is_owner(session: &Session) -> Result<bool, AppError> {
+    Ok(session
+        .get::<bool>("owner")
+        .await
+        .map_err(AppError::internal("get owner"))?
+        .unwrap_or(false))
+}
+
+pub(crate) async fn was_me(state: &AppState, tokens: &TokenPair) -> Result<bool, AppError> {
+    let me = state
+        .spotify
+        .me(&tokens.access_token)
+        .await
+        .map_err(AppError::spotify("get me"))?;
+    Ok(me.id == crate::ME)
+}
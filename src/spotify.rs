@@ -0,0 +1,282 @@
+use crate::{metrics::Metrics, Listens, MaybeAuth, Me, TokenPair, Track};
+use std::sync::Arc;
+use url::Url;
+
+pub const SPOTIFY_API_URL: &str = "https://api.spotify.com/";
+pub const SPOTIFY_ACCOUNTS_URL: &str = "https://accounts.spotify.com/";
+
+pub const SCOPE: &str = "user-read-recently-played user-modify-playback-state";
+
+/// the most `tracks` takes at once
+pub const TRACKS_AT_ONCE: usize = 50;
+
+#[derive(serde::Deserialize)]
+struct Tracks {
+    /// null where an id isn't a track
+    tracks: Vec<Option<Track>>,
+}
+
+#[derive(Debug)]
+pub enum SpotifyError {
+    Url(url::ParseError),
+    Request(reqwest::Error),
+    Status {
+        status: u16,
+        body: String,
+    },
+    Json {
+        err: serde_json::Error,
+        body: String,
+    },
+}
+
+impl SpotifyError {
+    /// the response body, if spotify sent us something we couldn't use
+    pub fn body(&self) -> Option<&str> {
+        match self {
+            SpotifyError::Status { body, .. } | SpotifyError::Json { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+
+    /// spotify didn't like our token, or our refresh token has been revoked and
+    /// only logging in again will help
+    pub fn is_unauthorized(&self) -> bool {
+        match self {
+            SpotifyError::Status { status: 401, .. } => true,
+            SpotifyError::Status { status: 400, body } => {
+                serde_json::from_str::<serde_json::Value>(body)
+                    .is_ok_and(|body| body["error"] == "invalid_grant")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpotifyError::Url(err) => write!(f, "{err}"),
+            SpotifyError::Request(err) => write!(f, "{err}"),
+            SpotifyError::Status { status, .. } => write!(f, "spotify returned {status}"),
+            SpotifyError::Json { err, .. } => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for SpotifyError {}
+
+impl From<url::ParseError> for SpotifyError {
+    fn from(err: url::ParseError) -> Self {
+        SpotifyError::Url(err)
+    }
+}
+
+impl From<reqwest::Error> for SpotifyError {
+    fn from(err: reqwest::Error) -> Self {
+        SpotifyError::Request(err)
+    }
+}
+
+/// talks to spotify. cheap to clone, the underlying connection pool is shared.
+///
+/// the api and accounts urls are configurable so tests can point it at a fake.
+#[derive(Clone, Debug)]
+pub struct SpotifyClient {
+    http: reqwest::Client,
+    api_url: Url,
+    accounts_url: Url,
+    client_id: String,
+    client_secret: String,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// make sure joining onto the url appends rather than replacing the last segment
+fn as_base(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+impl SpotifyClient {
+    pub fn new(
+        http: reqwest::Client,
+        client_id: &str,
+        client_secret: &str,
+        api_url: Url,
+        accounts_url: Url,
+    ) -> Self {
+        SpotifyClient {
+            http,
+            api_url: as_base(api_url),
+            accounts_url: as_base(accounts_url),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            metrics: None,
+        }
+    }
+
+    /// count every request we make, by endpoint and status
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn api_url(&self) -> &Url {
+        &self.api_url
+    }
+
+    pub fn accounts_url(&self) -> &Url {
+        &self.accounts_url
+    }
+
+    /// where to send the user to log in
+    pub fn authorize_url(&self, redirect_uri: &Url) -> Result<Url, SpotifyError> {
+        let mut url = self.accounts_url.join("authorize")?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", redirect_uri.as_str())
+            .append_pair("scope", SCOPE);
+        Ok(url)
+    }
+
+    /// trade the code from the authorize redirect for tokens
+    pub async fn request_token(
+        &self,
+        code: &str,
+        redirect_uri: &Url,
+    ) -> Result<TokenPair, SpotifyError> {
+        self.token(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+        ])
+        .await
+    }
+
+    /// spotify may or may not give us a new refresh token
+    pub async fn refresh_token(
+        &self,
+        refresh_token: &str,
+        redirect_uri: &Url,
+    ) -> Result<MaybeAuth, SpotifyError> {
+        self.token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("redirect_uri", redirect_uri.as_str()),
+        ])
+        .await
+    }
+
+    pub async fn recently_played(
+        &self,
+        access_token: &str,
+        limit: u32,
+    ) -> Result<Listens, SpotifyError> {
+        let request = self
+            .http
+            .get(self.api_url.join("v1/me/player/recently-played")?)
+            .bearer_auth(access_token)
+            .query(&[("limit", limit)]);
+        let response = text(self.send("recently-played", request).await?).await?;
+
+        tracing::debug!(
+            "recently-played: {:?}",
+            &response[..response.floor_char_boundary(50)]
+        );
+        parse(response)
+    }
+
+    /// up to `TRACKS_AT_ONCE`, leaving out any spotify doesn't know
+    pub async fn tracks(
+        &self,
+        access_token: &str,
+        ids: &[String],
+    ) -> Result<Vec<Track>, SpotifyError> {
+        let request = self
+            .http
+            .get(self.api_url.join("v1/tracks")?)
+            .bearer_auth(access_token)
+            .query(&[("ids", ids.join(","))]);
+        let response = text(self.send("tracks", request).await?).await?;
+        let tracks: Tracks = parse(response)?;
+        Ok(tracks.tracks.into_iter().flatten().collect())
+    }
+
+    pub async fn me(&self, access_token: &str) -> Result<Me, SpotifyError> {
+        let request = self
+            .http
+            .get(self.api_url.join("v1/me")?)
+            .bearer_auth(access_token);
+        let response = text(self.send("me", request).await?).await?;
+
+        tracing::debug!("get me: {:?}", response);
+        parse(response)
+    }
+
+    /// can we get to spotify at all? any response counts, even a 401
+    pub async fn ping(&self, timeout: std::time::Duration) -> Result<(), SpotifyError> {
+        let request = self.http.get(self.api_url.clone()).timeout(timeout);
+        self.send("ping", request).await?;
+        Ok(())
+    }
+
+    async fn token<T: serde::de::DeserializeOwned>(
+        &self,
+        params: &[(&str, &str)],
+    ) -> Result<T, SpotifyError> {
+        let mut token_url = self.accounts_url.join("api/token")?;
+        token_url
+            .query_pairs_mut()
+            .extend_pairs(params)
+            .append_pair("client_id", &self.client_id)
+            .append_pair("client_secret", &self.client_secret);
+        tracing::trace!("requesting {}", token_url.as_str());
+
+        let request = self
+            .http
+            .post(token_url)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Content-Length", "0");
+        let response = text(self.send("token", request).await?).await?;
+
+        tracing::debug!("token: {:?}", response);
+        parse(response)
+    }
+
+    async fn send(
+        &self,
+        endpoint: &str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, SpotifyError> {
+        let response = request.send().await;
+        if let Some(metrics) = &self.metrics {
+            let status = match &response {
+                Ok(response) => response.status().as_str().to_owned(),
+                Err(_) => String::from("error"),
+            };
+            metrics.spotify_requests.inc(&[endpoint, &status]);
+        }
+        Ok(response?)
+    }
+}
+
+async fn text(response: reqwest::Response) -> Result<String, SpotifyError> {
+    let status = response.status();
+    let body = response.text().await?;
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(SpotifyError::Status {
+            status: status.as_u16(),
+            body,
+        })
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(body: String) -> Result<T, SpotifyError> {
+    serde_json::from_str(&body).map_err(|err| SpotifyError::Json { err, body })
+}
@@ -0,0 +1,211 @@
+//! how long, not just how often. counts a listen as the whole track, since
+//! recently-played doesn't say if it was skipped
+
+use crate::{
+    chart,
+    db::{self, ArtistTime, ListeningTime},
+    error::{wants_json, AppError},
+    escape,
+    export::Range,
+    server::AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{Datelike, NaiveDate};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+/// how many artists the page and the json list
+const ARTIST_LIMIT: u32 = 50;
+
+/// how to group listens, as sqlite `strftime` formats. utc, like everything
+/// else in the database
+pub(crate) const DAY: &str = "%Y-%m-%d";
+/// iso weeks, like 2024-W01
+const WEEK: &str = "%G-W%V";
+const MONTH: &str = "%Y-%m";
+const YEAR: &str = "%Y";
+
+#[derive(Debug, serde::Serialize)]
+pub struct Total {
+    pub listens: i64,
+    pub ms: i64,
+    pub minutes: f64,
+    pub untimed: i64,
+}
+
+impl Total {
+    /// everything in `times`, however it's grouped
+    pub fn of(times: &[ListeningTime]) -> Total {
+        let ms = times.iter().map(|time| time.ms).sum();
+        Total {
+            listens: times.iter().map(|time| time.listens).sum(),
+            ms,
+            minutes: (ms as f64 / 6000.0).round() / 10.0,
+            untimed: times.iter().map(|time| time.untimed).sum(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ListeningStats {
+    pub total: Total,
+    pub days: Vec<ListeningTime>,
+    pub weeks: Vec<ListeningTime>,
+    pub months: Vec<ListeningTime>,
+    pub years: Vec<ListeningTime>,
+    pub artists: Vec<ArtistTime>,
+}
+
+pub async fn listening_stats(
+    pool: &SqlitePool,
+    range: Range,
+) -> Result<ListeningStats, sqlx::Error> {
+    let (from, to) = range.bounds();
+    let (from, to) = (from.as_deref(), to.as_deref());
+
+    let days = db::listening_time(pool, DAY, from, to).await?;
+    let weeks = db::listening_time(pool, WEEK, from, to).await?;
+    let months = db::listening_time(pool, MONTH, from, to).await?;
+    let years = db::listening_time(pool, YEAR, from, to).await?;
+    let artists = db::artist_time(pool, from, to, ARTIST_LIMIT).await?;
+
+    Ok(ListeningStats {
+        total: Total::of(&years),
+        days,
+        weeks,
+        months,
+        years,
+        artists,
+    })
+}
+
+/// like 12h 5m
+pub fn format_ms(ms: i64) -> String {
+    let minutes = ms / 60_000;
+    format!("{}h {}m", minutes / 60, minutes % 60)
+}
+
+fn minutes(times: &[ListeningTime]) -> Vec<(String, i64)> {
+    times
+        .iter()
+        .map(|time| (time.period.clone(), time.ms / 60_000))
+        .collect()
+}
+
+/// `?from=2024-01-01&to=2024-12-31`, or everything
+pub async fn page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(range): Query<Range>,
+) -> Result<Response, AppError> {
+    let stats = listening_stats(&state.pool, range)
+        .await
+        .map_err(AppError::database("listening stats"))?;
+
+    if wants_json(&headers) {
+        return Ok(Json(stats).into_response());
+    }
+
+    let config = &state.config;
+    let mut page = String::from(
+        r#"<!doctype html>
+<html>
+  <head><title>NOT LAST.FM: stats</title></head>
+  <style>
+table, td, th {
+    border: 1px solid #090;
+    border-collapse: collapse;
+    padding-left: 4pt;
+    padding-right: 8pt;
+}
+  </style>
+  <body>
+    <h1>how long has zack been listening?</h1>
+"#,
+    );
+    page.push_str(&format!("    <p>{}</p>\n", config.get_new_link("back")));
+
+    let total = &stats.total;
+    page.push_str(&format!(
+        "    <p>{} over {} listens</p>\n",
+        format_ms(total.ms),
+        total.listens,
+    ));
+    if total.untimed > 0 {
+        page.push_str(&format!(
+            "    <p><em>{} of them aren't counted, we don't know how long they are</em></p>\n",
+            total.untimed
+        ));
+    }
+
+    // most recent year first
+    let days = stats
+        .days
+        .iter()
+        .filter_map(|day| {
+            let date = NaiveDate::parse_from_str(&day.period, "%Y-%m-%d").ok()?;
+            Some((date, day.ms / 60_000))
+        })
+        .collect::<HashMap<_, _>>();
+    let mut years = days.keys().map(|day| day.year()).collect::<Vec<_>>();
+    years.sort_unstable();
+    years.dedup();
+    for year in years.into_iter().rev() {
+        page.push_str(&format!("    <h2>{year}</h2>\n"));
+        page.push_str(&chart::calendar(year, &days, "minutes"));
+    }
+
+    if !stats.months.is_empty() {
+        page.push_str("    <h2>minutes a week</h2>\n");
+        page.push_str(&chart::bars(&minutes(&stats.weeks)));
+        page.push_str("    <h2>minutes a month</h2>\n");
+        page.push_str(&chart::bars(&minutes(&stats.months)));
+
+        page.push_str("    <h2>by year</h2>\n");
+        page.push_str("    <table><tr><th>year</th><th>time</th><th>listens</th><th></th></tr>\n");
+        for year in &stats.years {
+            let wrapped = year
+                .period
+                .parse()
+                .ok()
+                .and_then(|year| {
+                    Some((
+                        NaiveDate::from_ymd_opt(year, 1, 1)?,
+                        NaiveDate::from_ymd_opt(year, 12, 31)?,
+                    ))
+                })
+                .map(|(from, to)| config.wrapped_link(from, to, "wrapped"))
+                .unwrap_or_default();
+            page.push_str(&format!(
+                "    <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                year.period,
+                format_ms(year.ms),
+                year.listens,
+                wrapped,
+            ));
+        }
+        page.push_str("    </table>\n");
+    }
+
+    if !stats.artists.is_empty() {
+        page.push_str("    <h2>by artist</h2>\n");
+        page.push_str("    <table><tr><th>artist</th><th>time</th><th>listens</th></tr>\n");
+        for artist in &stats.artists {
+            page.push_str(&format!(
+                "    <tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                config.artist_link(&artist.id, &escape(&artist.name)),
+                format_ms(artist.ms),
+                artist.listens,
+            ));
+        }
+        page.push_str("    </table>\n");
+    }
+
+    page.push_str("  </body>\n</html>\n");
+    Ok(Html(page).into_response())
+}
@@ -0,0 +1,204 @@
+//! serving https without a reverse proxy in front of us
+
+use crate::TlsConfig;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::Uri,
+    response::Redirect,
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+use tokio_native_tls::TlsAcceptor;
+use tower::ServiceExt;
+use url::Url;
+
+/// long enough for a slow phone, short enough that idle connections don't pile up
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// certbot writes the certificate and key one after the other, and openssl
+/// doesn't notice when they don't match until someone tries to connect
+const SETTLE: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read { path: PathBuf, err: std::io::Error },
+    Identity(native_tls::Error),
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Read { path, err } => write!(f, "couldn't read {}: {err}", path.display()),
+            TlsError::Identity(err) => write!(f, "bad certificate or key: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+struct Loaded {
+    acceptor: TlsAcceptor,
+    modified: [Option<SystemTime>; 2],
+}
+
+/// the certificate and key, picked up again whenever they change on disk, so
+/// a renewal doesn't need a restart
+pub struct Tls {
+    config: TlsConfig,
+    current: RwLock<Loaded>,
+}
+
+fn modified(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+    [&config.cert, &config.key].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+}
+
+fn settled(modified: &[Option<SystemTime>]) -> bool {
+    modified.iter().flatten().all(|modified| {
+        modified
+            .elapsed()
+            .is_ok_and(|since_modified| since_modified >= SETTLE)
+    })
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|err| TlsError::Read {
+        path: path.to_owned(),
+        err,
+    })
+}
+
+fn load(config: &TlsConfig) -> Result<Loaded, TlsError> {
+    // before reading, so a write that lands in between gets noticed next time
+    let modified = modified(config);
+    let identity = native_tls::Identity::from_pkcs8(&read(&config.cert)?, &read(&config.key)?)
+        .map_err(TlsError::Identity)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(TlsError::Identity)?;
+    Ok(Loaded {
+        acceptor: acceptor.into(),
+        modified,
+    })
+}
+
+impl Tls {
+    pub fn new(config: TlsConfig) -> Result<Tls, TlsError> {
+        let current = RwLock::new(load(&config)?);
+        Ok(Tls { config, current })
+    }
+
+    /// for the next handshake. looking for a new certificate touches the disk,
+    /// so that happens off the async threads
+    async fn acceptor(self: Arc<Self>) -> TlsAcceptor {
+        let tls = self.clone();
+        if let Err(err) = tokio::task::spawn_blocking(move || tls.reload()).await {
+            tracing::error!("checking for a new certificate: {err}");
+        }
+        self.current.read().unwrap().acceptor.clone()
+    }
+
+    fn reload(&self) {
+        let modified = modified(&self.config);
+        if modified != self.current.read().unwrap().modified && settled(&modified) {
+            let mut current = self.current.write().unwrap();
+            match load(&self.config) {
+                Ok(loaded) => {
+                    tracing::info!("loaded the new certificate");
+                    *current = loaded;
+                }
+                // the other half of a renewal changes it again, and we'll
+                // try then
+                Err(err) => {
+                    tracing::error!("keeping the old certificate: {err}");
+                    current.modified = modified;
+                }
+            }
+        }
+    }
+}
+
+/// like `axum::serve`, with a handshake first. once `shutdown` finishes, stops
+/// accepting and waits for open connections to finish what they're doing
+pub async fn serve(
+    listener: TcpListener,
+    tls: Arc<Tls>,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let (stream, remote) = match accepted {
+            Ok(accepted) => accepted,
+            // out of file descriptors or similar, which waiting might fix
+            Err(err) => {
+                tracing::error!("accept: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let acceptor = tls.acceptor().await;
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        tracing::debug!("handshake with {remote}: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!("handshake with {remote} timed out");
+                        return;
+                    }
+                };
+
+            let service =
+                TowerToHyperService::new(app.map_request(move |mut request: Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(remote));
+                    request
+                }));
+            let builder = Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            if let Err(err) = watcher.watch(connection.into_owned()).await {
+                tracing::debug!("connection from {remote}: {err}");
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+/// sends everything to the same path on `base_url`
+pub fn redirect(base_url: Url) -> Router {
+    Router::new().fallback(move |uri: Uri| async move {
+        let mut url = base_url.clone();
+        url.set_path(uri.path());
+        url.set_query(uri.query());
+        Redirect::permanent(url.as_str())
+    })
+}
@@ -0,0 +1,256 @@
+//! like spotify wrapped, but for any range and it doesn't go away. one html
+//! page with nothing to load, so it can be saved or sent around
+
+use crate::{
+    chart,
+    db::{self, ArtistTime, ListeningTime, NewArtist, TopAlbum, TopTrack},
+    error::{wants_json, AppError},
+    escape,
+    export::Range,
+    server::AppState,
+    stats::{self, format_ms, Total},
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDate;
+use sqlx::SqlitePool;
+
+/// how long each top list is
+const TOP: u32 = 10;
+
+/// the most days in a row with at least one listen
+#[derive(Debug, serde::Serialize)]
+pub struct Streak {
+    pub days: i64,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Wrapped {
+    /// what the range works out to, `to` being exclusive
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub total: Total,
+    pub top_tracks: Vec<TopTrack>,
+    pub top_artists: Vec<ArtistTime>,
+    pub top_albums: Vec<TopAlbum>,
+    pub busiest_day: Option<ListeningTime>,
+    pub longest_streak: Option<Streak>,
+    pub new_artists: Vec<NewArtist>,
+    /// listens in each hour of the day, utc, starting at midnight
+    pub hours: Vec<i64>,
+}
+
+pub async fn wrapped(pool: &SqlitePool, range: Range) -> Result<Wrapped, sqlx::Error> {
+    let (from, to) = range.bounds();
+    let (start, end) = (from.as_deref(), to.as_deref());
+
+    let days = db::listening_time(pool, stats::DAY, start, end).await?;
+    let mut hours = vec![0; 24];
+    for hour in db::listening_time(pool, "%H", start, end).await? {
+        if let Some(listens) = hour
+            .period
+            .parse::<usize>()
+            .ok()
+            .and_then(|hour| hours.get_mut(hour))
+        {
+            *listens = hour.listens;
+        }
+    }
+
+    Ok(Wrapped {
+        total: Total::of(&days),
+        top_tracks: db::top_tracks(pool, start, end, TOP).await?,
+        top_artists: db::artist_time(pool, start, end, TOP).await?,
+        top_albums: db::top_albums_between(pool, start, end, TOP).await?,
+        new_artists: db::new_artists(pool, start, end).await?,
+        longest_streak: longest_streak(&days),
+        busiest_day: days.into_iter().max_by_key(|day| (day.listens, day.ms)),
+        hours,
+        from,
+        to,
+    })
+}
+
+/// `days` are in order, and only the ones with listens
+fn longest_streak(days: &[ListeningTime]) -> Option<Streak> {
+    let mut longest: Option<Streak> = None;
+    let mut current: Option<(NaiveDate, NaiveDate)> = None;
+    for day in days
+        .iter()
+        .filter_map(|day| NaiveDate::parse_from_str(&day.period, "%Y-%m-%d").ok())
+    {
+        let (from, to) = match current {
+            Some((from, to)) if to.succ_opt() == Some(day) => (from, day),
+            _ => (day, day),
+        };
+        current = Some((from, to));
+        let days = (to - from).num_days() + 1;
+        if longest.as_ref().is_none_or(|longest| days > longest.days) {
+            longest = Some(Streak { days, from, to });
+        }
+    }
+    longest
+}
+
+/// like "2024-01-01 to 2024-12-31"
+pub fn describe(range: &Range) -> String {
+    match (range.from, range.to) {
+        (Some(from), Some(to)) => format!("{from} to {to}"),
+        (Some(from), None) => format!("since {from}"),
+        (None, Some(to)) => format!("up to {to}"),
+        (None, None) => String::from("all time"),
+    }
+}
+
+pub fn html(wrapped: &Wrapped, title: &str) -> String {
+    let title = escape(title);
+    let mut page = format!(
+        r#"<!doctype html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>NOT LAST.FM wrapped: {title}</title>
+  </head>
+  <style>
+body {{
+    font-family: sans-serif;
+    max-width: 800px;
+}}
+table, td, th {{
+    border: 1px solid #090;
+    border-collapse: collapse;
+    padding-left: 4pt;
+    padding-right: 8pt;
+}}
+.big {{
+    font-size: 200%;
+    color: #090;
+}}
+  </style>
+  <body>
+    <h1>zack's wrapped: {title}</h1>
+"#
+    );
+
+    let total = &wrapped.total;
+    if total.listens == 0 {
+        page.push_str("    <p>nothing was listened to</p>\n  </body>\n</html>\n");
+        return page;
+    }
+    page.push_str(&format!(
+        "    <p><span class=big>{}</span> minutes over {} listens ({})</p>\n",
+        total.ms / 60_000,
+        total.listens,
+        format_ms(total.ms),
+    ));
+    if total.untimed > 0 {
+        page.push_str(&format!(
+            "    <p><em>{} of them aren't counted, we don't know how long they are</em></p>\n",
+            total.untimed
+        ));
+    }
+
+    page.push_str("    <h2>top tracks</h2>\n    <table><tr><th></th><th>track</th><th>artist</th><th>plays</th></tr>\n");
+    for (i, track) in wrapped.top_tracks.iter().enumerate() {
+        page.push_str(&format!(
+            "    <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            i + 1,
+            escape(&track.name),
+            escape(&track.artist),
+            track.plays,
+        ));
+    }
+    page.push_str("    </table>\n");
+
+    if !wrapped.top_artists.is_empty() {
+        page.push_str("    <h2>top artists</h2>\n    <table><tr><th></th><th>artist</th><th>time</th><th>plays</th></tr>\n");
+        for (i, artist) in wrapped.top_artists.iter().enumerate() {
+            page.push_str(&format!(
+                "    <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                i + 1,
+                escape(&artist.name),
+                format_ms(artist.ms),
+                artist.listens,
+            ));
+        }
+        page.push_str("    </table>\n");
+    }
+
+    page.push_str(
+        "    <h2>top albums</h2>\n    <table><tr><th></th><th>album</th><th>plays</th></tr>\n",
+    );
+    for (i, album) in wrapped.top_albums.iter().enumerate() {
+        page.push_str(&format!(
+            "    <tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            i + 1,
+            escape(&album.name),
+            album.plays,
+        ));
+    }
+    page.push_str("    </table>\n");
+
+    if let Some(day) = &wrapped.busiest_day {
+        page.push_str(&format!(
+            "    <h2>busiest day</h2>\n    <p><span class=big>{}</span> {} listens, {}</p>\n",
+            day.period,
+            day.listens,
+            format_ms(day.ms),
+        ));
+    }
+    if let Some(streak) = &wrapped.longest_streak {
+        page.push_str(&format!(
+            "    <h2>longest streak</h2>\n    <p><span class=big>{} {}</span> in a row, {} to {}</p>\n",
+            streak.days,
+            if streak.days == 1 { "day" } else { "days" },
+            streak.from,
+            streak.to,
+        ));
+    }
+
+    if !wrapped.new_artists.is_empty() {
+        let names = wrapped
+            .new_artists
+            .iter()
+            .map(|artist| escape(&artist.name))
+            .collect::<Vec<_>>();
+        page.push_str(&format!(
+            "    <h2>new artists</h2>\n    <p><span class=big>{}</span> found for the first time: {}</p>\n",
+            names.len(),
+            names.join(", "),
+        ));
+    }
+
+    let hours = wrapped
+        .hours
+        .iter()
+        .enumerate()
+        .map(|(hour, listens)| (format!("{hour:02}:00"), *listens))
+        .collect::<Vec<_>>();
+    page.push_str("    <h2>by hour of the day (utc)</h2>\n");
+    page.push_str(&chart::bars(&hours));
+
+    page.push_str("  </body>\n</html>\n");
+    page
+}
+
+/// `?from=2024-01-01&to=2024-12-31`, or everything
+pub async fn page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(range): Query<Range>,
+) -> Result<Response, AppError> {
+    let report = wrapped(&state.pool, range)
+        .await
+        .map_err(AppError::database("wrapped"))?;
+
+    if wants_json(&headers) {
+        return Ok(Json(report).into_response());
+    }
+    Ok(Html(html(&report, &describe(&range))).into_response())
+}
@@ -0,0 +1,178 @@
+mod common;
+
+use common::{FakeSpotify, TestApp, Webhook};
+use spotti::alert::{Alert, AlertConfig, Alerter, Sink};
+
+fn alert(message: &str) -> Alert {
+    Alert {
+        context: String::from("recently-played"),
+        message: message.into(),
+        details: None,
+    }
+}
+
+#[tokio::test]
+async fn webhook() {
+    let webhook = Webhook::start().await;
+    let alerter = Alerter::new(
+        AlertConfig::new(vec![Sink::Webhook(webhook.url.clone())], None, None),
+        reqwest::Client::new(),
+    );
+
+    assert!(
+        alerter
+            .alert(Alert {
+                details: Some(String::from("{\"error\": \"oh no\"}")),
+                ..alert("spotify returned 500")
+            })
+            .await
+    );
+
+    let received = webhook.received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["content"], received[0]["text"]);
+
+    let content = received[0]["content"].as_str().unwrap();
+    assert!(content.starts_with("recently-played: spotify returned 500"));
+    assert!(content.contains("oh no"));
+}
+
+#[tokio::test]
+async fn dedup() {
+    let webhook = Webhook::start().await;
+    let alerter = Alerter::new(
+        AlertConfig::new(vec![Sink::Webhook(webhook.url.clone())], None, None),
+        reqwest::Client::new(),
+    );
+    assert!(alerter.alert(alert("same thing")).await);
+    assert!(!alerter.alert(alert("same thing")).await);
+    assert!(!alerter.alert(alert("same thing")).await);
+    assert!(alerter.alert(alert("something else")).await);
+
+    assert_eq!(webhook.messages().len(), 2);
+}
+
+#[tokio::test]
+async fn dedup_window_expires() {
+    let webhook = Webhook::start().await;
+    let alerter = Alerter::new(
+        AlertConfig::new(vec![Sink::Webhook(webhook.url.clone())], Some(1), None),
+        reqwest::Client::new(),
+    );
+
+    assert!(alerter.alert(alert("same thing")).await);
+    assert!(!alerter.alert(alert("same thing")).await);
+    assert!(!alerter.alert(alert("same thing")).await);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert!(alerter.alert(alert("same thing")).await);
+
+    let messages = webhook.messages();
+    assert_eq!(messages.len(), 2);
+    assert!(
+        messages[1].contains("and 2 more like it"),
+        "{}",
+        messages[1]
+    );
+}
+
+#[tokio::test]
+async fn rate_limit() {
+    let webhook = Webhook::start().await;
+    let alerter = Alerter::new(
+        AlertConfig::new(vec![Sink::Webhook(webhook.url.clone())], None, Some(2)),
+        reqwest::Client::new(),
+    );
+
+    assert!(alerter.alert(alert("one")).await);
+    assert!(alerter.alert(alert("two")).await);
+    assert!(!alerter.alert(alert("three")).await);
+    assert!(!alerter.alert(alert("four")).await);
+
+    assert_eq!(webhook.messages().len(), 2);
+}
+
+#[tokio::test]
+async fn command() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    let alerter = Alerter::new(
+        AlertConfig::new(
+            vec![Sink::Command(vec![
+                String::from("sh"),
+                String::from("-c"),
+                format!(
+                    "cat > {} && echo \"$SPOTTI_ALERT_CONTEXT\" >> {}",
+                    out.display(),
+                    out.display()
+                ),
+            ])],
+            None,
+            None,
+        ),
+        reqwest::Client::new(),
+    );
+
+    assert!(alerter.alert(alert("spotify returned 502")).await);
+    assert_eq!(
+        std::fs::read_to_string(out).unwrap(),
+        "recently-played: spotify returned 502recently-played\n"
+    );
+}
+
+#[tokio::test]
+async fn errors_are_alerted() {
+    let spotify = FakeSpotify::start().await;
+    let webhook = Webhook::start().await;
+    let url = webhook.url.clone();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), move |config| {
+        format!("{config}\n[[alerts]]\ntype = \"webhook\"\nurl = \"{url}\"\n")
+    })
+    .await;
+
+    app.get("refresh").await;
+    app.authorize().await;
+    spotify.expire_token();
+    app.get("").await;
+    app.get("").await;
+
+    common::eventually(|| !webhook.messages().is_empty()).await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let messages = webhook.messages();
+    assert_eq!(messages.len(), 1, "{:?}", messages);
+    assert!(messages[0].contains("recently-played: spotify returned 401"));
+}
+
+#[tokio::test]
+async fn poll_once_failures_are_alerted() {
+    let spotify = FakeSpotify::start().await;
+    let webhook = Webhook::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+
+    // logged in, but getting listens won't work
+    let api_url = format!("spotify_api_url = \"{}\"", spotify.url);
+    let url = webhook.url.clone();
+    app.reload(|config| {
+        format!("{config}\n[[alerts]]\ntype = \"webhook\"\nurl = \"{url}\"\n")
+            .replace(&api_url, "spotify_api_url = \"http://127.0.0.1:1/\"")
+    })
+    .unwrap();
+    let state = app.reloadable.state();
+    assert!(spotti::cli::poll_once(&state).await.is_err());
+
+    // already sent, it's a cron job and about to exit
+    let messages = webhook.messages();
+    assert_eq!(messages.len(), 1, "{:?}", messages);
+    assert!(
+        messages[0].starts_with("recently-played: "),
+        "{:?}",
+        messages
+    );
+
+    let context: String = sqlx::query_scalar("select context from errors")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!(context, "recently-played");
+}
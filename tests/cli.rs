@@ -0,0 +1,424 @@
+mod common;
+
+use clap::Parser;
+use common::{FakeSpotify, TestApp};
+use spotti::{
+    cli::{self, Cli, CliError, Command},
+    export::{self, Bound, Format, Range},
+    server::AppState,
+    Config, StringConfig,
+};
+use std::convert::TryFrom;
+use tokio::net::TcpListener;
+use url::Url;
+
+/// another spotti on the same database, like running the binary again
+async fn restart(app: &TestApp, spotify: &FakeSpotify) -> AppState {
+    let config: StringConfig = toml::from_str(&common::config(&app.dir, spotify)).unwrap();
+    AppState::new(Config::try_from(config).unwrap())
+        .await
+        .unwrap()
+}
+
+#[test]
+fn subcommands() {
+    let cli = Cli::try_parse_from(["spotti", "-c", "x.toml", "export", "-o", "out.jsonl"]).unwrap();
+    assert_eq!(cli.config.to_str(), Some("x.toml"));
+    assert!(matches!(
+        cli.command,
+        Command::Export {
+            output: Some(_),
+            format: Format::Json,
+            from: None,
+            to: None,
+        }
+    ));
+
+    let cli = Cli::try_parse_from([
+        "spotti",
+        "export",
+        "--format",
+        "csv",
+        "--from",
+        "2024-01-01",
+        "--to",
+        "2024-02-01T12:00:00Z",
+    ])
+    .unwrap();
+    assert!(matches!(
+        cli.command,
+        Command::Export {
+            format: Format::Csv,
+            from: Some(Bound::Day(_)),
+            to: Some(Bound::Instant(_)),
+            ..
+        }
+    ));
+    assert!(Cli::try_parse_from(["spotti", "export", "--from", "last tuesday"]).is_err());
+
+    let cli = Cli::try_parse_from([
+        "spotti",
+        "wrapped",
+        "--from",
+        "2024-01-01",
+        "-o",
+        "2024.html",
+    ])
+    .unwrap();
+    assert!(matches!(
+        cli.command,
+        Command::Wrapped {
+            output: Some(_),
+            from: Some(Bound::Day(_)),
+            to: None,
+        }
+    ));
+
+    let cli = Cli::try_parse_from(["spotti", "poll-once"]).unwrap();
+    assert_eq!(cli.config.to_str(), Some("spotti.toml"));
+    assert!(matches!(cli.command, Command::PollOnce));
+
+    let cli = Cli::try_parse_from(["spotti", "auth"]).unwrap();
+    let Command::Auth { redirect_uri } = cli.command else {
+        panic!("{:?}", cli.command);
+    };
+    assert_eq!(redirect_uri.port(), Some(8888));
+
+    let cli = Cli::try_parse_from(["spotti", "lastfm-auth"]).unwrap();
+    assert!(matches!(cli.command, Command::LastfmAuth));
+
+    assert!(Cli::try_parse_from(["spotti", "dance"]).is_err());
+}
+
+#[tokio::test]
+async fn poll_once() {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    let app = TestApp::start(&spotify).await;
+
+    // nobody has logged in yet
+    assert!(spotti::server::poll_once(&app.state).await.is_err());
+
+    app.authorize().await;
+    spotify.expire_token();
+
+    // a fresh process only has what got saved to the database
+    let state = restart(&app, &spotify).await;
+    assert_eq!(spotti::server::poll_once(&state).await.unwrap(), 1);
+    assert_eq!(spotti::server::poll_once(&state).await.unwrap(), 0);
+    assert_eq!(app.count().await, 1);
+}
+
+#[tokio::test]
+async fn export_import() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.insert(
+        "Flim",
+        "Come To Daddy",
+        "Aphex Twin",
+        "2024-01-02T00:00:00.000Z",
+        "track-2",
+    )
+    .await;
+    app.insert(
+        "Windowlicker",
+        "Windowlicker",
+        "Aphex Twin",
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    )
+    .await;
+
+    let mut exported = Vec::new();
+    assert_eq!(
+        export::write(
+            &app.state.pool,
+            Format::Json,
+            Range::default(),
+            &mut exported
+        )
+        .await
+        .unwrap(),
+        2
+    );
+    let exported = String::from_utf8(exported).unwrap();
+    let lines = exported.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("Windowlicker"), "{}", lines[0]);
+
+    let other = TestApp::start(&spotify).await;
+    other
+        .insert(
+            "Flim",
+            "Come To Daddy",
+            "Aphex Twin",
+            "2024-01-02T00:00:00.000Z",
+            "track-2",
+        )
+        .await;
+    assert_eq!(
+        cli::import(&other.state, exported.as_bytes())
+            .await
+            .unwrap(),
+        (2, 1)
+    );
+    assert_eq!(other.count().await, 2);
+}
+
+#[tokio::test]
+async fn import_is_all_or_nothing() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let input = r#"{"name":"Xtal","album":"SAW 85-92","artist":"Aphex Twin","date":"2024-01-01T00:00:00.000Z","id":"track-3"}
+
+not json
+"#;
+    let err = cli::import(&app.state, input.as_bytes()).await.unwrap_err();
+    assert!(matches!(err, CliError::Input { line: 3, .. }), "{}", err);
+    assert_eq!(app.count().await, 0);
+}
+
+#[tokio::test]
+async fn stats() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.insert(
+        "Xtal",
+        "SAW 85-92",
+        "Aphex Twin",
+        "2024-01-01T00:00:00.000Z",
+        "track-3",
+    )
+    .await;
+    app.insert(
+        "Xtal",
+        "SAW 85-92",
+        "Aphex Twin",
+        "2024-01-02T00:00:00.000Z",
+        "track-3",
+    )
+    .await;
+    app.insert(
+        "Avril 14th",
+        "Drukqs",
+        "Aphex Twin",
+        "2024-01-03T00:00:00.000Z",
+        "track-4",
+    )
+    .await;
+
+    let mut out = Vec::new();
+    cli::stats(&app.state, &mut out).await.unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("listens: 3\n"), "{}", out);
+    assert!(out.contains("tracks: 2\n"), "{}", out);
+    assert!(out.contains("artists: 1\n"), "{}", out);
+    assert!(
+        out.contains("first listen: 2024-01-01T00:00:00.000Z\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains("1. Xtal by Aphex Twin (2)\n"), "{}", out);
+    // inserted without their lengths
+    assert!(out.contains("time listened: 0h 0m\n"), "{}", out);
+}
+
+#[tokio::test]
+async fn wrapped() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    for date in [
+        "2023-12-31T00:00:00.000Z",
+        "2024-01-01T00:00:00.000Z",
+        "2024-01-02T00:00:00.000Z",
+    ] {
+        app.insert("Xtal", "SAW 85-92", "Aphex Twin", date, "track-3")
+            .await;
+    }
+
+    let mut out = Vec::new();
+    let range = Range {
+        from: Some("2024-01-01".parse().unwrap()),
+        to: Some("2024-12-31".parse().unwrap()),
+    };
+    cli::wrapped(&app.state, range, &mut out).await.unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("<!doctype html>"), "{}", out);
+    assert!(
+        out.contains("zack's wrapped: 2024-01-01 to 2024-12-31"),
+        "{}",
+        out
+    );
+    assert!(out.contains("over 2 listens"), "{}", out);
+    // inserted without their lengths
+    assert!(out.contains("2 of them aren't counted"), "{}", out);
+    assert!(
+        out.contains("<span class=big>2 days</span> in a row"),
+        "{}",
+        out
+    );
+}
+
+async fn start_auth(
+    state: &AppState,
+    paste: &'static [u8],
+) -> (Url, tokio::task::JoinHandle<Result<(), CliError>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let redirect_uri = Url::parse(&format!(
+        "http://{}/callback",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+
+    let state = state.clone();
+    let uri = redirect_uri.clone();
+    let handle = tokio::spawn(async move { cli::auth(&state, listener, &uri, paste).await });
+    (redirect_uri, handle)
+}
+
+#[tokio::test]
+async fn auth_callback() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let (mut redirect_uri, handle) = start_auth(&app.state, b"").await;
+    redirect_uri.set_query(Some(&format!("code={}", common::CODE)));
+    let response = reqwest::get(redirect_uri).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    handle.await.unwrap().unwrap();
+    assert!(app.state.global_auth.read().unwrap().is_some());
+
+    let state = restart(&app, &spotify).await;
+    let global_auth = state.global_auth.read().unwrap().clone().unwrap();
+    assert_eq!(global_auth.0.refresh_token, common::REFRESH_TOKEN);
+}
+
+#[tokio::test]
+async fn auth_paste() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let (_, handle) = start_auth(
+        &app.state,
+        b"\nhttp://127.0.0.1:8888/callback?code=fake-code\n",
+    )
+    .await;
+    handle.await.unwrap().unwrap();
+    assert!(app.state.global_auth.read().unwrap().is_some());
+}
+
+#[tokio::test]
+async fn auth_not_the_owner() {
+    let spotify = FakeSpotify::start().await;
+    spotify.set_user("someone-else");
+    let app = TestApp::start(&spotify).await;
+
+    let (_, handle) = start_auth(&app.state, b"fake-code\n").await;
+    let err = handle.await.unwrap().unwrap_err();
+    assert!(matches!(err, CliError::Auth(_)), "{}", err);
+    assert!(app.state.global_auth.read().unwrap().is_none());
+}
+
+#[tokio::test]
+async fn auth_denied() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let (mut redirect_uri, handle) = start_auth(&app.state, b"").await;
+    redirect_uri.set_query(Some("error=access_denied"));
+    reqwest::get(redirect_uri).await.unwrap();
+
+    let err = handle.await.unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "spotify said access_denied");
+}
+
+#[tokio::test]
+async fn backfill() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+
+    // from before there was a catalog
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    app.insert(
+        "Windowlicker",
+        "Windowlicker",
+        "Aphex Twin",
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    )
+    .await;
+    app.insert("Gone", "Gone", "Nobody", "2024-01-02T00:00:00.000Z", "gone")
+        .await;
+
+    assert_eq!(spotti::cli::backfill(&app.state).await.unwrap(), (2, 1));
+    let (name, album): (String, String) = sqlx::query_as(
+        "select tracks.name, albums.name from tracks join albums on albums.id = tracks.album_id",
+    )
+    .fetch_one(&app.state.pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        (name.as_str(), album.as_str()),
+        ("Windowlicker", "Windowlicker")
+    );
+
+    // only what spotify didn't know is left
+    assert_eq!(spotti::cli::backfill(&app.state).await.unwrap(), (1, 0));
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_ingestion() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+
+    let ingesting = app.state.ingesting.clone().read_owned().await;
+    let state = app.state.clone();
+    let shutdown = tokio::spawn(async move { state.shutdown().await });
+
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!shutdown.is_finished());
+    assert!(!app.state.pool.is_closed());
+
+    drop(ingesting);
+    shutdown.await.unwrap();
+    assert!(app.state.pool.is_closed());
+
+    let state = restart(&app, &spotify).await;
+    assert!(state.global_auth.read().unwrap().is_some());
+}
+
+#[tokio::test]
+async fn nothing_is_ingested_after_shutdown() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    let refreshes = spotify.state.token_requests.lock().unwrap().len();
+
+    app.state.shutdown().await;
+    spotify.play("late", "album", &["artist"], "2024-01-01T00:00:00Z", "late");
+    let err = spotti::cli::poll_once(&app.state).await.unwrap_err();
+    assert!(err.to_string().contains("shutting down"), "{}", err);
+    // didn't even get a new token
+    assert_eq!(
+        spotify.state.token_requests.lock().unwrap().len(),
+        refreshes
+    );
+    assert_eq!(app.count().await, 0);
+}
@@ -0,0 +1,725 @@
+#![allow(dead_code)]
+
+//! an in-process stand-in for the parts of spotify we talk to, and a way to
+//! run spotti itself against it
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing, Json, Router,
+};
+use serde_json::{json, Value};
+use spotti::{
+    server::{AppState, Reloadable},
+    Config, SpotifyClient, StringConfig,
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tempfile::TempDir;
+use url::Url;
+
+pub const CLIENT_ID: &str = "fake-client-id";
+pub const CLIENT_SECRET: &str = "fake-client-secret";
+pub const CODE: &str = "fake-code";
+pub const REFRESH_TOKEN: &str = "fake-refresh-token";
+
+pub struct FakeSpotify {
+    pub url: Url,
+    pub state: Arc<FakeState>,
+}
+
+pub struct FakeState {
+    pub user_id: Mutex<String>,
+    pub access_token: Mutex<String>,
+    pub listens: Mutex<Vec<Value>>,
+    pub token_requests: Mutex<Vec<HashMap<String, String>>>,
+    pub pings: Mutex<u32>,
+    issued: Mutex<u32>,
+}
+
+impl FakeSpotify {
+    pub async fn start() -> FakeSpotify {
+        let state = Arc::new(FakeState {
+            user_id: Mutex::new(String::from(spotti::ME)),
+            access_token: Mutex::new(String::new()),
+            listens: Mutex::new(Vec::new()),
+            token_requests: Mutex::new(Vec::new()),
+            pings: Mutex::new(0),
+            issued: Mutex::new(0),
+        });
+
+        let app = Router::new()
+            .route("/", routing::get(ping))
+            .route("/api/token", routing::post(token))
+            .route("/v1/me", routing::get(me))
+            .route("/v1/tracks", routing::get(tracks))
+            .route(
+                "/v1/me/player/recently-played",
+                routing::get(recently_played),
+            )
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        FakeSpotify { url, state }
+    }
+
+    pub fn client(&self) -> SpotifyClient {
+        SpotifyClient::new(
+            reqwest::Client::new(),
+            CLIENT_ID,
+            CLIENT_SECRET,
+            self.url.clone(),
+            self.url.clone(),
+        )
+    }
+
+    pub fn set_user(&self, id: &str) {
+        *self.state.user_id.lock().unwrap() = id.into();
+    }
+
+    /// invalidate whatever access token is outstanding, like an hour passing
+    pub fn expire_token(&self) {
+        *self.state.access_token.lock().unwrap() = String::new();
+    }
+
+    pub fn access_token(&self) -> String {
+        self.state.access_token.lock().unwrap().clone()
+    }
+
+    pub fn play(&self, name: &str, album: &str, artists: &[&str], played_at: &str, id: &str) {
+        self.play_listen(listen(name, album, artists, played_at, id));
+    }
+
+    /// one made with `listen` and then messed with
+    pub fn play_listen(&self, listen: Value) {
+        self.state.listens.lock().unwrap().insert(0, listen);
+    }
+}
+
+pub fn listen(name: &str, album: &str, artists: &[&str], played_at: &str, id: &str) -> Value {
+    let artists = artists
+        .iter()
+        .map(|name| json!({ "name": name, "id": format!("artist-{name}") }))
+        .collect::<Vec<_>>();
+
+    json!({
+        "played_at": played_at,
+        "track": {
+            "album": {
+                "album_type": "album",
+                "artists": artists,
+                "name": album,
+                "type": "album",
+                "id": format!("album-{album}"),
+                "release_date": "1999-03-22",
+                "release_date_precision": "day",
+                "images": [
+                    { "url": format!("https://i.scdn.co/image/{album}-640"), "height": 640, "width": 640 },
+                    { "url": format!("https://i.scdn.co/image/{album}-64"), "height": 64, "width": 64 },
+                ],
+            },
+            "artists": artists,
+            "name": name,
+            "type": "track",
+            "id": id,
+            "duration_ms": 240_000,
+            "explicit": false,
+            "popularity": 50,
+            "track_number": 1,
+            "disc_number": 1,
+            "external_ids": { "isrc": format!("ISRC-{id}") },
+        },
+        "context": {
+            "type": "playlist",
+            "uri": "spotify:playlist:fake-playlist",
+            "href": "https://api.spotify.com/v1/playlists/fake-playlist",
+            "external_urls": { "spotify": "https://open.spotify.com/playlist/fake-playlist" },
+        },
+    })
+}
+
+async fn ping(State(state): State<Arc<FakeState>>) -> StatusCode {
+    *state.pings.lock().unwrap() += 1;
+    StatusCode::NOT_FOUND
+}
+
+fn error(status: StatusCode, error: &str) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+fn authorized(state: &FakeState, headers: &HeaderMap) -> bool {
+    let token = state.access_token.lock().unwrap();
+    !token.is_empty()
+        && headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value == format!("Bearer {token}"))
+}
+
+fn issue(state: &FakeState) -> String {
+    let mut issued = state.issued.lock().unwrap();
+    *issued += 1;
+    let token = format!("fake-access-token-{issued}");
+    *state.access_token.lock().unwrap() = token.clone();
+    token
+}
+
+async fn token(
+    State(state): State<Arc<FakeState>>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    state.token_requests.lock().unwrap().push(query.clone());
+
+    if query.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+        || query.get("client_secret").map(String::as_str) != Some(CLIENT_SECRET)
+    {
+        return error(StatusCode::BAD_REQUEST, "invalid_client");
+    }
+
+    match query.get("grant_type").map(String::as_str) {
+        Some("authorization_code") if query.get("code").map(String::as_str) == Some(CODE) => {
+            Json(json!({
+                "access_token": issue(&state),
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "refresh_token": REFRESH_TOKEN,
+            }))
+            .into_response()
+        }
+
+        Some("refresh_token")
+            if query.get("refresh_token").map(String::as_str) == Some(REFRESH_TOKEN) =>
+        {
+            Json(json!({
+                "access_token": issue(&state),
+                "token_type": "Bearer",
+                "expires_in": 3600,
+            }))
+            .into_response()
+        }
+
+        _ => error(StatusCode::BAD_REQUEST, "invalid_grant"),
+    }
+}
+
+async fn me(State(state): State<Arc<FakeState>>, headers: HeaderMap) -> Response {
+    if !authorized(&state, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "invalid token");
+    }
+
+    Json(json!({ "id": *state.user_id.lock().unwrap() })).into_response()
+}
+
+async fn recently_played(
+    State(state): State<Arc<FakeState>>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&state, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "invalid token");
+    }
+
+    let limit = query
+        .get("limit")
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(20);
+
+    let listens = state.listens.lock().unwrap();
+    let items = listens.iter().take(limit).cloned().collect::<Vec<_>>();
+    Json(json!({ "items": items })).into_response()
+}
+
+/// whichever of `ids` have been played
+async fn tracks(
+    State(state): State<Arc<FakeState>>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&state, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "invalid token");
+    }
+
+    let ids = query["ids"].split(',').collect::<Vec<_>>();
+    assert!(ids.len() <= 50, "{} tracks at once", ids.len());
+    let listens = state.listens.lock().unwrap();
+    let tracks = ids
+        .iter()
+        .map(|id| {
+            listens
+                .iter()
+                .find(|listen| listen["track"]["id"] == *id)
+                .map(|listen| listen["track"].clone())
+        })
+        .collect::<Vec<_>>();
+    Json(json!({ "tracks": tracks })).into_response()
+}
+
+/// spotti listening on a random port with a fresh database
+pub struct TestApp {
+    pub url: Url,
+    pub client: reqwest::Client,
+    pub dir: TempDir,
+    pub state: AppState,
+    pub reloadable: Reloadable,
+    spotify_url: Url,
+}
+
+pub fn config(dir: &TempDir, spotify: &FakeSpotify) -> String {
+    config_with(dir, &spotify.url)
+}
+
+fn config_with(dir: &TempDir, spotify: &Url) -> String {
+    format!(
+        r#"
+db_file = "{db_file}"
+error_file = "{error_file}"
+bot_pidfile = "{bot_pidfile}"
+
+client_id = "{CLIENT_ID}"
+client_secret = "{CLIENT_SECRET}"
+
+base_url = "http://spotti.test/"
+authorize_endpoint = "authorize"
+refresh_endpoint = "refresh"
+get_new_endpoint = ""
+show_all_endpoint = "all"
+uptime_endpoint = "uptime"
+
+get_new_limit = 2
+
+address = "127.0.0.1:0"
+
+spotify_api_url = "{spotify}"
+spotify_accounts_url = "{spotify}"
+"#,
+        db_file = dir.path().join("recents.db").display(),
+        error_file = dir.path().join("error").display(),
+        bot_pidfile = dir.path().join("pid").display(),
+        spotify = spotify,
+    )
+}
+
+impl TestApp {
+    pub async fn start(spotify: &FakeSpotify) -> TestApp {
+        let dir = tempfile::tempdir().unwrap();
+        TestApp::start_with(spotify, dir, |config| config).await
+    }
+
+    pub async fn start_with(
+        spotify: &FakeSpotify,
+        dir: TempDir,
+        edit: impl FnOnce(String) -> String,
+    ) -> TestApp {
+        // an empty file is an empty database, the migrations do the rest
+        std::fs::File::create(dir.path().join("recents.db")).unwrap();
+
+        let string_config: StringConfig = toml::from_str(&edit(config(&dir, spotify))).unwrap();
+        let state = AppState::new(Config::try_from(string_config).unwrap())
+            .await
+            .unwrap();
+        let reloadable = Reloadable::new(state.clone());
+        let app = reloadable.router();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+
+        let client = reqwest::Client::builder()
+            .cookie_store(true)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        TestApp {
+            url,
+            client,
+            dir,
+            state,
+            reloadable,
+            spotify_url: spotify.url.clone(),
+        }
+    }
+
+    /// like editing the config file and sending SIGHUP
+    pub fn reload(
+        &self,
+        edit: impl FnOnce(String) -> String,
+    ) -> Result<Vec<&'static str>, spotti::config::ConfigError> {
+        let config = config_with(&self.dir, &self.spotify_url);
+        let string_config: StringConfig = toml::from_str(&edit(config)).unwrap();
+        self.reloadable.reload(Config::try_from(string_config)?)
+    }
+
+    pub fn db_file(&self) -> PathBuf {
+        self.dir.path().join("recents.db")
+    }
+
+    pub async fn get(&self, path: &str) -> reqwest::Response {
+        self.client
+            .get(self.url.join(path).unwrap())
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// go through the authorize callback like spotify just sent us back
+    pub async fn authorize(&self) -> reqwest::Response {
+        self.get(&format!("authorize?code={CODE}")).await
+    }
+
+    pub async fn insert(&self, name: &str, album: &str, artist: &str, date: &str, id: &str) {
+        let pool = sqlx::SqlitePool::connect(&self.db_file().display().to_string())
+            .await
+            .unwrap();
+        sqlx::query("insert into songs values ($1, $2, $3, $4, $5)")
+            .bind(name)
+            .bind(album)
+            .bind(artist)
+            .bind(date)
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+    }
+
+    pub async fn count(&self) -> i64 {
+        let pool = sqlx::SqlitePool::connect(&self.db_file().display().to_string())
+            .await
+            .unwrap();
+        let count = sqlx::query_scalar("select count(*) from songs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        count
+    }
+}
+
+/// alerts and such go out in the background
+pub async fn eventually(mut check: impl FnMut() -> bool) {
+    for _ in 0..100 {
+        if check() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("gave up waiting");
+}
+
+/// somewhere for alerts to go
+pub struct Webhook {
+    pub url: Url,
+    pub received: Arc<Mutex<Vec<Value>>>,
+}
+
+impl Webhook {
+    pub async fn start() -> Webhook {
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let app =
+            Router::new()
+                .route(
+                    "/hook",
+                    routing::post(
+                        |State(received): State<Arc<Mutex<Vec<Value>>>>,
+                         Json(body): Json<Value>| async move {
+                            received.lock().unwrap().push(body);
+                            StatusCode::NO_CONTENT
+                        },
+                    ),
+                )
+                .with_state(received.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Webhook { url, received }
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|body| body["content"].as_str().unwrap().to_owned())
+            .collect()
+    }
+}
+
+pub const LISTENBRAINZ_TOKEN: &str = "fake-listenbrainz-token";
+/// listenbrainz turns down anything from before 2002-10-01
+pub const LISTENBRAINZ_EARLIEST: i64 = 1033430400;
+
+/// listenbrainz's submit-listens, and a switch to make it fall over
+pub struct FakeListenBrainz {
+    pub url: Url,
+    pub listens: Arc<Mutex<Vec<Value>>>,
+    pub down: Arc<Mutex<bool>>,
+}
+
+#[derive(Clone)]
+struct FakeListenBrainzState {
+    listens: Arc<Mutex<Vec<Value>>>,
+    down: Arc<Mutex<bool>>,
+}
+
+async fn submit_listens(
+    State(state): State<FakeListenBrainzState>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if *state.down.lock().unwrap() {
+        return error(StatusCode::SERVICE_UNAVAILABLE, "down for maintenance");
+    }
+    if headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        != Some(&format!("Token {LISTENBRAINZ_TOKEN}"))
+    {
+        return error(StatusCode::UNAUTHORIZED, "invalid token");
+    }
+
+    let payload = body["payload"].as_array().unwrap();
+    // like the real one, any bad listen fails the lot
+    for listen in payload {
+        let metadata = &listen["track_metadata"];
+        if metadata["artist_name"].as_str().is_none_or(str::is_empty)
+            || metadata["track_name"].as_str().is_none_or(str::is_empty)
+        {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "artist_name and track_name are required",
+            );
+        }
+        if listen["listened_at"].as_i64() < Some(LISTENBRAINZ_EARLIEST) {
+            return error(StatusCode::BAD_REQUEST, "value of listened_at is too low");
+        }
+    }
+    assert_eq!(
+        body["listen_type"],
+        if payload.len() == 1 {
+            "single"
+        } else {
+            "import"
+        }
+    );
+    state
+        .listens
+        .lock()
+        .unwrap()
+        .extend(payload.iter().cloned());
+    Json(json!({ "status": "ok" })).into_response()
+}
+
+impl FakeListenBrainz {
+    pub async fn start() -> FakeListenBrainz {
+        let state = FakeListenBrainzState {
+            listens: Arc::new(Mutex::new(Vec::new())),
+            down: Arc::new(Mutex::new(false)),
+        };
+        let app = Router::new()
+            .route("/1/submit-listens", routing::post(submit_listens))
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        FakeListenBrainz {
+            url,
+            listens: state.listens,
+            down: state.down,
+        }
+    }
+
+    /// what to add to the config to use it
+    pub fn config(&self) -> String {
+        format!(
+            "\n[listenbrainz]\ntoken = \"{LISTENBRAINZ_TOKEN}\"\napi_url = \"{}\"\n",
+            self.url
+        )
+    }
+
+    pub fn track_names(&self) -> Vec<String> {
+        self.listens
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|listen| {
+                listen["track_metadata"]["track_name"]
+                    .as_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+}
+
+pub const LASTFM_API_KEY: &str = "fake-lastfm-api-key";
+pub const LASTFM_API_SECRET: &str = "fake-lastfm-api-secret";
+pub const LASTFM_TOKEN: &str = "fake-lastfm-token";
+pub const LASTFM_SESSION_KEY: &str = "fake-lastfm-session-key";
+
+/// one track.scrobble call, `artist[0]` becoming `artist` and so on
+pub type Batch = Vec<HashMap<String, String>>;
+
+/// the bits of the last.fm api we use, checking signatures like the real one
+pub struct FakeLastFm {
+    pub url: Url,
+    pub batches: Arc<Mutex<Vec<Batch>>>,
+    pub down: Arc<Mutex<bool>>,
+}
+
+#[derive(Clone)]
+struct FakeLastFmState {
+    batches: Arc<Mutex<Vec<Batch>>>,
+    down: Arc<Mutex<bool>>,
+}
+
+fn lastfm_error(status: StatusCode, code: u32, message: &str) -> Response {
+    (status, Json(json!({ "error": code, "message": message }))).into_response()
+}
+
+fn lastfm_signature(params: &[(String, String)]) -> String {
+    use md5::{Digest, Md5};
+
+    let mut sorted = params
+        .iter()
+        .filter(|(name, _)| name != "format" && name != "api_sig")
+        .collect::<Vec<_>>();
+    sorted.sort();
+    let mut md5 = Md5::new();
+    for (name, value) in sorted {
+        md5.update(name);
+        md5.update(value);
+    }
+    md5.update(LASTFM_API_SECRET);
+    format!("{:x}", md5.finalize())
+}
+
+async fn lastfm_api(
+    State(state): State<FakeLastFmState>,
+    axum::Form(params): axum::Form<Vec<(String, String)>>,
+) -> Response {
+    if *state.down.lock().unwrap() {
+        return lastfm_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            16,
+            "There was a temporary error processing your request",
+        );
+    }
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    assert_eq!(param("format"), Some("json"));
+    if param("api_key") != Some(LASTFM_API_KEY) {
+        return lastfm_error(StatusCode::FORBIDDEN, 10, "Invalid API key");
+    }
+    if param("api_sig") != Some(&lastfm_signature(&params)) {
+        return lastfm_error(StatusCode::FORBIDDEN, 13, "Invalid method signature");
+    }
+
+    match param("method") {
+        Some("auth.getToken") => Json(json!({ "token": LASTFM_TOKEN })).into_response(),
+        Some("auth.getSession") if param("token") == Some(LASTFM_TOKEN) => Json(json!({
+            "session": { "name": "owner", "key": LASTFM_SESSION_KEY, "subscriber": 0 }
+        }))
+        .into_response(),
+        Some("auth.getSession") => lastfm_error(StatusCode::FORBIDDEN, 4, "Invalid token"),
+
+        Some("track.scrobble") => {
+            if param("sk") != Some(LASTFM_SESSION_KEY) {
+                return lastfm_error(StatusCode::FORBIDDEN, 9, "Invalid session key");
+            }
+            let mut batch = Batch::new();
+            for (key, value) in &params {
+                let Some((name, i)) = key.strip_suffix(']').and_then(|key| key.split_once('['))
+                else {
+                    continue;
+                };
+                let i = i.parse::<usize>().unwrap();
+                if batch.len() <= i {
+                    batch.resize(i + 1, HashMap::new());
+                }
+                batch[i].insert(name.to_owned(), value.clone());
+            }
+            assert!(batch.len() <= 50, "{} scrobbles at once", batch.len());
+            // like the real one, which ignores them rather than failing
+            let ignored = batch
+                .iter()
+                .filter(|scrobble| scrobble.get("artist").is_none_or(String::is_empty))
+                .count();
+            let accepted = batch.len() - ignored;
+            state.batches.lock().unwrap().push(batch);
+            // and every number is a string
+            Json(json!({
+                "scrobbles": {
+                    "@attr": { "accepted": accepted.to_string(), "ignored": ignored.to_string() }
+                }
+            }))
+            .into_response()
+        }
+
+        _ => lastfm_error(StatusCode::BAD_REQUEST, 3, "Invalid method"),
+    }
+}
+
+impl FakeLastFm {
+    pub async fn start() -> FakeLastFm {
+        let state = FakeLastFmState {
+            batches: Arc::new(Mutex::new(Vec::new())),
+            down: Arc::new(Mutex::new(false)),
+        };
+        let app = Router::new()
+            .route("/2.0/", routing::post(lastfm_api))
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/2.0/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        FakeLastFm {
+            url,
+            batches: state.batches,
+            down: state.down,
+        }
+    }
+
+    /// what to add to the config to use it, without a session key
+    pub fn config(&self) -> String {
+        format!(
+            "\n[lastfm]\napi_key = \"{LASTFM_API_KEY}\"\napi_secret = \"{LASTFM_API_SECRET}\"\napi_url = \"{}\"\n",
+            self.url
+        )
+    }
+
+    /// and with one
+    pub fn config_with_session(&self) -> String {
+        self.config() + &format!("session_key = \"{LASTFM_SESSION_KEY}\"\n")
+    }
+
+    pub fn scrobbles(&self) -> Vec<HashMap<String, String>> {
+        self.batches.lock().unwrap().concat()
+    }
+}
@@ -0,0 +1,503 @@
+mod common;
+
+use common::FakeSpotify;
+use spotti::config::{self, ConfigError, ConfigProblem};
+use std::{collections::HashMap, io::Write};
+
+async fn parse(edit: impl FnOnce(String) -> String) -> Result<spotti::Config, ConfigError> {
+    parse_env(edit, &[]).await
+}
+
+async fn parse_env(
+    edit: impl FnOnce(String) -> String,
+    env: &[(&str, &str)],
+) -> Result<spotti::Config, ConfigError> {
+    let spotify = FakeSpotify::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::File::create(dir.path().join("recents.db")).unwrap();
+    let env = env
+        .iter()
+        .map(|(var, value)| (var.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+    config::parse_with_env(&edit(common::config(&dir, &spotify)), |var| {
+        env.get(var).cloned()
+    })
+}
+
+#[tokio::test]
+async fn valid() {
+    let config = parse(|config| config).await.unwrap();
+    assert_eq!(config.show_all_url.as_str(), "http://spotti.test/all");
+    assert_eq!(config.metrics_url.as_str(), "http://spotti.test/metrics");
+}
+
+#[tokio::test]
+async fn syntax() {
+    let err = parse(|config| format!("{config}\nthis isn't toml"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.problems[..], [ConfigProblem::Syntax(_)]),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn every_missing_key() {
+    let err = parse(|config| {
+        config
+            .lines()
+            .filter(|line| !line.starts_with("client_secret") && !line.starts_with("address"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+    .await
+    .unwrap_err();
+
+    assert!(
+        matches!(
+            err.problems[..],
+            [
+                ConfigProblem::MissingKey("client_secret"),
+                ConfigProblem::MissingKey("address")
+            ]
+        ),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn wrong_type() {
+    let err = parse(|config| config.replace("get_new_limit = 2", "get_new_limit = \"two\""))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.problems[..], [ConfigProblem::Invalid(_)]),
+        "{}",
+        err
+    );
+    assert!(err.to_string().contains("get_new_limit"), "{}", err);
+}
+
+#[tokio::test]
+async fn missing_and_invalid() {
+    let err = parse(|config| {
+        config
+            .lines()
+            .filter(|line| !line.starts_with("address"))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .replace("http://spotti.test/", "not a url")
+            .replace("get_new_limit = 2", "get_new_limit = \"two\"")
+            .replace("show_all_endpoint = \"all\"", "show_all_endpoint = 5")
+    })
+    .await
+    .unwrap_err();
+
+    assert!(
+        matches!(
+            err.problems[..],
+            [
+                ConfigProblem::MissingKey("address"),
+                ConfigProblem::Invalid(_),
+                ConfigProblem::Invalid(_),
+                ConfigProblem::BadUrl {
+                    key: "base_url",
+                    ..
+                },
+            ]
+        ),
+        "{}",
+        err
+    );
+    let message = err.to_string();
+    assert!(message.contains("get_new_limit"), "{}", message);
+    assert!(message.contains("show_all_endpoint"), "{}", message);
+}
+
+#[tokio::test]
+async fn everything_at_once() {
+    let err = parse(|config| {
+        config
+            .replace("recents.db", "nope.db")
+            .replace("http://spotti.test/", "mailto:zack@spotti.test")
+            .replace("127.0.0.1:0", "localhost")
+            .replace("uptime_endpoint = \"uptime\"", "uptime_endpoint = \"all\"")
+            + "\n[[alerts]]\ntype = \"webhook\"\nurl = \"not a url\"\n"
+    })
+    .await
+    .unwrap_err();
+
+    let message = err.to_string();
+    assert!(
+        message.starts_with("5 problems with the config:"),
+        "{}",
+        message
+    );
+    assert!(
+        matches!(
+            err.problems[..],
+            [
+                ConfigProblem::DbFileMissing(_),
+                ConfigProblem::CannotBeABase {
+                    key: "base_url",
+                    ..
+                },
+                ConfigProblem::DuplicateEndpoint { .. },
+                ConfigProblem::BadAddress { .. },
+                ConfigProblem::BadAlert { index: 1, .. },
+            ]
+        ),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("show_all_endpoint, uptime_endpoint would all be at /all"),
+        "{}",
+        message
+    );
+}
+
+#[tokio::test]
+async fn base_url_needs_a_domain() {
+    let err = parse(|config| config.replace("http://spotti.test/", "http://127.0.0.1/"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.problems[..], [ConfigProblem::NoDomain(_)]),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn default_endpoints_collide_too() {
+    let err = parse(|config| {
+        config.replace(
+            "show_all_endpoint = \"all\"",
+            "show_all_endpoint = \"health\"",
+        )
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad config: show_all_endpoint, health_endpoint would all be at /health"
+    );
+}
+
+#[tokio::test]
+async fn env_overrides() {
+    let config = parse_env(
+        |config| config,
+        &[
+            ("SPOTTI_GET_NEW_LIMIT", "5"),
+            ("SPOTTI_BASE_URL", "https://spotti.example/"),
+            ("SPOTTI_CLIENT_SECRET", "from-env"),
+            (
+                "SPOTTI_ALERTS",
+                r#"[{ type = "webhook", url = "https://hooks.example/1" }]"#,
+            ),
+            ("SPOTTI_DISCORD_TOKEN", "token"),
+            ("SPOTTI_DISCORD_OWNER", "1234"),
+            ("SPOTTI_NOT_A_KEY", "whatever"),
+        ],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(config.get_new_limit, 5);
+    assert_eq!(config.show_all_url.as_str(), "https://spotti.example/all");
+    assert_eq!(config.client_secret, "from-env");
+    // plus the pidfile one from the file
+    assert!(config.alerts.sinks.len() >= 2);
+    let discord = config.discord.unwrap();
+    assert_eq!((discord.token.as_str(), discord.owner), ("token", 1234));
+}
+
+#[tokio::test]
+async fn env_fills_in_missing_keys() {
+    let config = parse_env(
+        |config| config.replace("client_id = ", "# client_id = "),
+        &[("SPOTTI_CLIENT_ID", "from-env")],
+    )
+    .await
+    .unwrap();
+    assert_eq!(config.client_id, "from-env");
+}
+
+#[tokio::test]
+async fn bad_env() {
+    let err = parse_env(
+        |config| config,
+        &[
+            ("SPOTTI_GET_NEW_LIMIT", "lots"),
+            ("SPOTTI_ALERTS", "[{ type = "),
+        ],
+    )
+    .await
+    .unwrap_err();
+
+    assert!(
+        matches!(
+            err.problems[..],
+            [ConfigProblem::BadEnv { .. }, ConfigProblem::BadEnv { .. }]
+        ),
+        "{}",
+        err
+    );
+    assert!(
+        err.to_string().contains("$SPOTTI_GET_NEW_LIMIT: "),
+        "{}",
+        err
+    );
+}
+
+fn secret_file(contents: &str) -> tempfile::NamedTempFile {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
+    file
+}
+
+#[tokio::test]
+async fn client_secret_file() {
+    let secret = secret_file("s3cret\n");
+    let path = secret.path().display().to_string();
+
+    let config = parse_env(
+        |config| {
+            config.replace(
+                &format!("client_secret = \"{}\"", common::CLIENT_SECRET),
+                &format!("client_secret_file = \"{path}\""),
+            )
+        },
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(config.client_secret, "s3cret");
+
+    // the environment wins over the file, whichever way around
+    let config = parse_env(|config| config, &[("SPOTTI_CLIENT_SECRET_FILE", &path)])
+        .await
+        .unwrap();
+    assert_eq!(config.client_secret, "s3cret");
+}
+
+#[tokio::test]
+async fn secret_problems() {
+    let secret = secret_file("s3cret");
+    let path = secret.path().display().to_string();
+
+    let err = parse_env(
+        |config| format!("client_secret_file = \"{path}\"\n{config}"),
+        &[],
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(
+            err.problems[..],
+            [ConfigProblem::SecretConflict("client_secret")]
+        ),
+        "{}",
+        err
+    );
+
+    let err = parse_env(
+        |config| config,
+        &[("SPOTTI_CLIENT_SECRET_FILE", "/does/not/exist")],
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(err.problems[..], [ConfigProblem::SecretFile { .. }]),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn nested_endpoints() {
+    let config = parse(|config| {
+        config
+            .replace(
+                "uptime_endpoint = \"uptime\"",
+                "uptime_endpoint = \"stats/uptime/\"",
+            )
+            .replace(
+                "show_all_endpoint = \"all\"",
+                "show_all_endpoint = \"api/listens\"",
+            )
+    })
+    .await
+    .unwrap();
+    assert_eq!(config.uptime_url.path(), "/stats/uptime");
+    assert_eq!(config.show_all_url.path(), "/api/listens");
+    assert_eq!(config.get_new_url.path(), "/");
+}
+
+#[tokio::test]
+async fn path_prefix() {
+    let config = parse(|config| format!("path_prefix = \"/spotti/\"\n{config}"))
+        .await
+        .unwrap();
+    assert_eq!(config.get_new_url.as_str(), "http://spotti.test/spotti/");
+    assert_eq!(
+        config.authorize_url.as_str(),
+        "http://spotti.test/spotti/authorize"
+    );
+
+    // the base url's own path comes first
+    let config = parse(|config| {
+        format!("path_prefix = \"spotti\"\n{config}")
+            .replace("http://spotti.test/", "http://spotti.test/music")
+    })
+    .await
+    .unwrap();
+    assert_eq!(config.show_all_url.path(), "/music/spotti/all");
+}
+
+#[tokio::test]
+async fn dot_segments() {
+    let err = parse(|config| {
+        format!("path_prefix = \"..\"\n{config}").replace(
+            "show_all_endpoint = \"all\"",
+            "show_all_endpoint = \"a/./b\"",
+        )
+    })
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(
+            err.problems[..],
+            [
+                ConfigProblem::BadEndpoint {
+                    key: "path_prefix",
+                    ..
+                },
+                ConfigProblem::BadEndpoint {
+                    key: "show_all_endpoint",
+                    ..
+                }
+            ]
+        ),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn tls() {
+    let https = |config: String| config.replace("http://spotti.test/", "https://spotti.test/");
+    let config = parse(|config| {
+        format!("{}\n[tls]\ncert = \"tests/certs/cert1.pem\"\nkey = \"tests/certs/key1.pem\"\nredirect_address = \"127.0.0.1:8080\"\n", https(config))
+    })
+    .await
+    .unwrap();
+    let tls = config.tls.unwrap();
+    assert_eq!(tls.redirect_address.unwrap().port(), 8080);
+
+    let err = parse(|config| {
+        format!(
+            "{}\n[tls]\ncert = \"tests/certs/cert1.pem\"\nkey = \"nope.pem\"\n",
+            https(config)
+        )
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad config: `tls.key` nope.pem does not exist"
+    );
+
+    // the redirect would send everyone round in circles
+    let err = parse(|config| {
+        format!(
+            "{config}\n[tls]\ncert = \"tests/certs/cert1.pem\"\nkey = \"tests/certs/key1.pem\"\n"
+        )
+    })
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(err.problems[..], [ConfigProblem::TlsWithoutHttps(_)]),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn listenbrainz() {
+    let config = parse(|config| config).await.unwrap();
+    assert!(config.listenbrainz.is_none());
+
+    let token = secret_file("lb-token\n");
+    let config = parse_env(
+        |config| config,
+        &[(
+            "SPOTTI_LISTENBRAINZ_TOKEN_FILE",
+            &token.path().display().to_string(),
+        )],
+    )
+    .await
+    .unwrap();
+    let listenbrainz = config.listenbrainz.unwrap();
+    assert_eq!(listenbrainz.token, "lb-token");
+    assert_eq!(
+        listenbrainz.api_url.as_str(),
+        "https://api.listenbrainz.org/"
+    );
+
+    let err =
+        parse(|config| format!("{config}\n[listenbrainz]\ntoken = \"t\"\napi_url = \"nope\"\n"))
+            .await
+            .unwrap_err();
+    assert!(
+        matches!(
+            err.problems[..],
+            [ConfigProblem::BadUrl {
+                key: "listenbrainz.api_url",
+                ..
+            }]
+        ),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn lastfm() {
+    let config = parse(|config| config).await.unwrap();
+    assert!(config.lastfm.is_none());
+
+    let session_key = secret_file("sk\n");
+    let config = parse_env(
+        |config| format!("{config}\n[lastfm]\napi_key = \"key\"\n"),
+        &[
+            ("SPOTTI_LASTFM_API_SECRET", "secret"),
+            (
+                "SPOTTI_LASTFM_SESSION_KEY_FILE",
+                &session_key.path().display().to_string(),
+            ),
+        ],
+    )
+    .await
+    .unwrap();
+    let lastfm = config.lastfm.unwrap();
+    assert_eq!(lastfm.api_key, "key");
+    assert_eq!(lastfm.api_secret, "secret");
+    assert_eq!(lastfm.session_key.as_deref(), Some("sk"));
+    assert_eq!(
+        lastfm.api_url.as_str(),
+        "https://ws.audioscrobbler.com/2.0/"
+    );
+
+    // needs a secret too
+    assert!(
+        parse(|config| format!("{config}\n[lastfm]\napi_key = \"key\"\n"))
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+async fn app_with_listens() -> (FakeSpotify, TestApp) {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2023-11-20T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+    );
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead", "Thom Yorke"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-02T00:00:00.000Z",
+        "track-1",
+    );
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+    (spotify, app)
+}
+
+async fn json(app: &TestApp, path: &str) -> Value {
+    let response = app
+        .client
+        .get(app.url.join(path).unwrap())
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn linked_from_the_main_page() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let page = app.get("").await.text().await.unwrap();
+    assert!(page.contains("<a href=http://spotti.test/track/track-3>Nude</a>"));
+    assert!(page.contains("<a href=http://spotti.test/album/album-In%20Rainbows>In Rainbows</a>"));
+    assert!(page.contains(
+        "<a href=http://spotti.test/artist/artist-Radiohead>Radiohead</a>, <a href=http://spotti.test/artist/artist-Thom%20Yorke>Thom Yorke</a>"
+    ));
+}
+
+#[tokio::test]
+async fn track() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let track = json(&app, "track/track-1").await;
+    assert_eq!(track["name"], "Windowlicker");
+    assert_eq!(track["plays"], 2);
+    assert_eq!(track["first"], "2023-11-20T00:00:00.000Z");
+    assert_eq!(track["last"], "2024-01-02T00:00:00.000Z");
+    // empty months too
+    assert_eq!(
+        track["months"],
+        serde_json::json!([
+            { "month": "2023-11", "plays": 1 },
+            { "month": "2023-12", "plays": 0 },
+            { "month": "2024-01", "plays": 1 },
+        ])
+    );
+    assert_eq!(track["listens"].as_array().unwrap().len(), 2);
+
+    let page = app.get("track/track-1").await.text().await.unwrap();
+    assert!(page.contains("<h1>Windowlicker</h1>"));
+    assert!(page.contains("2 plays"));
+    assert!(page.contains("<svg"));
+    assert!(page.contains("<title>2023-12: 0</title>"));
+    assert!(
+        page.contains("by <a href=http://spotti.test/artist/artist-Aphex%20Twin>Aphex Twin</a>")
+    );
+}
+
+#[tokio::test]
+async fn album_and_artist() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let album = json(&app, "album/album-Selected%20Ambient%20Works%2085-92").await;
+    assert_eq!(album["name"], "Selected Ambient Works 85-92");
+    assert_eq!(album["plays"], 1);
+
+    let artist = json(&app, "artist/artist-Aphex%20Twin").await;
+    assert_eq!(artist["name"], "Aphex Twin");
+    assert_eq!(artist["plays"], 3);
+    let tracks = artist["listens"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|listen| listen["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(tracks, ["Windowlicker", "Xtal", "Windowlicker"]);
+
+    // on someone else's track counts too
+    let artist = json(&app, "artist/artist-Thom%20Yorke").await;
+    assert_eq!(artist["plays"], 1);
+
+    let page = app
+        .get("artist/artist-Radiohead")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("<h1>Radiohead</h1>"));
+    assert!(page.contains("1 play "));
+}
+
+#[tokio::test]
+async fn unknown() {
+    let (_spotify, app) = app_with_listens().await;
+
+    for path in ["track/nope", "album/nope", "artist/nope"] {
+        assert_eq!(app.get(path).await.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn from_before_we_kept_track() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    sqlx::query("insert into songs values ('Xtal', 'SAW 85-92', 'Aphex Twin', $1, 'old-track')")
+        .bind("2019-01-01T00:00:00.000Z")
+        .execute(&app.state.pool)
+        .await
+        .unwrap();
+
+    // the track's still there, the album and artist just aren't links
+    let page = app.get("all").await.text().await.unwrap();
+    assert!(page.contains("<a href=http://spotti.test/track/old-track>Xtal</a>"));
+    assert!(page.contains("<td>SAW 85-92</td><td>Aphex Twin</td>"));
+    assert_eq!(json(&app, "track/old-track").await["plays"], 1);
+}
@@ -0,0 +1,100 @@
+#![cfg(feature = "discord")]
+
+mod common;
+
+use chrono::{Duration, SecondsFormat, Utc};
+use common::{FakeSpotify, TestApp};
+use spotti::discord::reply;
+
+fn ago(days: i64) -> String {
+    (Utc::now() - Duration::days(days)).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[tokio::test]
+async fn ignores_other_messages() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    assert_eq!(reply(&app.state, "hello").await.unwrap(), None);
+    assert_eq!(reply(&app.state, "").await.unwrap(), None);
+    assert_eq!(reply(&app.state, "!uptimes").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn uptime() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let uptime = reply(&app.state, "!uptime").await.unwrap().unwrap();
+    assert_eq!(uptime, "spotti: 0d 0h 0m 0s");
+}
+
+#[tokio::test]
+async fn now_playing() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    assert_eq!(
+        reply(&app.state, "!np").await.unwrap().unwrap(),
+        "nothing yet"
+    );
+
+    app.insert(
+        "Windowlicker",
+        "Windowlicker",
+        "Aphex Twin",
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    )
+    .await;
+    app.insert(
+        "Flim",
+        "Come To Daddy",
+        "Aphex Twin",
+        "2024-01-02T00:00:00.000Z",
+        "track-2",
+    )
+    .await;
+
+    assert_eq!(
+        reply(&app.state, "!np").await.unwrap().unwrap(),
+        "**Flim** by Aphex Twin on *Come To Daddy* <t:1704153600:R>"
+    );
+}
+
+#[tokio::test]
+async fn top() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    app.insert("Flim", "Come To Daddy", "Aphex Twin", &ago(1), "track-2")
+        .await;
+    app.insert("Flim", "Come To Daddy", "Aphex Twin", &ago(2), "track-2")
+        .await;
+    app.insert("Xtal", "SAW 85-92", "Aphex Twin", &ago(3), "track-3")
+        .await;
+    app.insert("Xtal", "SAW 85-92", "Aphex Twin", &ago(20), "track-3")
+        .await;
+    app.insert("Xtal", "SAW 85-92", "Aphex Twin", &ago(21), "track-3")
+        .await;
+
+    assert_eq!(
+        reply(&app.state, "!top").await.unwrap().unwrap(),
+        "top tracks, last 7 days:\n1. **Flim** by Aphex Twin (2)\n2. **Xtal** by Aphex Twin (1)"
+    );
+    assert_eq!(
+        reply(&app.state, "!top all").await.unwrap().unwrap(),
+        "top tracks, all time:\n1. **Xtal** by Aphex Twin (3)\n2. **Flim** by Aphex Twin (2)"
+    );
+    assert_eq!(
+        reply(&app.state, "!top 1000000000000")
+            .await
+            .unwrap()
+            .unwrap(),
+        "usage: !top [days|all]"
+    );
+    assert_eq!(
+        reply(&app.state, "!top -3").await.unwrap().unwrap(),
+        "usage: !top [days|all]"
+    );
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use spotti::export::{self, Format, Range};
+
+async fn app_with_listens(spotify: &FakeSpotify) -> TestApp {
+    let app = TestApp::start(spotify).await;
+    app.insert(
+        "Windowlicker",
+        "Windowlicker",
+        "Aphex Twin",
+        "2023-12-31T23:59:59.000Z",
+        "track-1",
+    )
+    .await;
+    app.insert(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        "Aphex Twin",
+        "2024-01-01T00:00:00.000Z",
+        "track-2",
+    )
+    .await;
+    app.insert(
+        "Nude",
+        "In Rainbows",
+        "Radiohead, Thom Yorke",
+        "2024-01-31T23:00:00.000Z",
+        "track-3",
+    )
+    .await;
+    app.insert(
+        "Flim",
+        "Come To Daddy",
+        "Aphex Twin",
+        "2024-02-01T00:00:00.000Z",
+        "track-4",
+    )
+    .await;
+    app
+}
+
+#[tokio::test]
+async fn csv_for_january() {
+    let spotify = FakeSpotify::start().await;
+    let app = app_with_listens(&spotify).await;
+
+    let response = app
+        .get("export?format=csv&from=2024-01-01&to=2024-01-31")
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/csv; charset=utf-8"
+    );
+    assert_eq!(
+        response.headers()["content-disposition"],
+        "attachment; filename=\"spotti.csv\""
+    );
+    assert_eq!(
+        response.text().await.unwrap(),
+        "date,artist,album,name,id
+2024-01-01T00:00:00.000Z,Aphex Twin,Selected Ambient Works 85-92,Xtal,track-2
+2024-01-31T23:00:00.000Z,\"Radiohead, Thom Yorke\",In Rainbows,Nude,track-3
+"
+    );
+}
+
+#[tokio::test]
+async fn scrobbler_log() {
+    let spotify = FakeSpotify::start().await;
+    let app = app_with_listens(&spotify).await;
+
+    let log = app
+        .get("export?format=scrobbler&from=2024-02-01T00:00:00Z")
+        .await
+        .text()
+        .await
+        .unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines[..2], ["#AUDIOSCROBBLER/1.1", "#TZ/UTC"]);
+    assert_eq!(
+        lines[3..],
+        ["Aphex Twin\tCome To Daddy\tFlim\t\t0\tL\t1706745600\t"]
+    );
+}
+
+#[tokio::test]
+async fn bad_query() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    assert_eq!(app.get("export?format=xml").await.status(), 400);
+    assert_eq!(app.get("export?from=yesterday").await.status(), 400);
+}
+
+#[tokio::test]
+async fn lots() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    let pool = sqlx::SqlitePool::connect(&app.db_file().display().to_string())
+        .await
+        .unwrap();
+    for i in 0..2000 {
+        sqlx::query("insert into songs values ('Xtal', 'SAW 85-92', 'Aphex Twin', $1, $2)")
+            .bind(format!("2024-01-01T00:00:{:02}.{:03}Z", i / 1000, i % 1000))
+            .bind(format!("track-{i}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    // more than fits in one chunk
+    let body = app.get("export").await.text().await.unwrap();
+    assert_eq!(body.lines().count(), 2000);
+    assert!(body.lines().last().unwrap().contains("track-1999"));
+
+    let mut out = Vec::new();
+    let written = export::write(&app.state.pool, Format::Csv, Range::default(), &mut out)
+        .await
+        .unwrap();
+    assert_eq!(written, 2000);
+}
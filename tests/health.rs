@@ -0,0 +1,103 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+async fn get_health(app: &TestApp) -> (StatusCode, Value) {
+    let response = app.get("health").await;
+    (response.status(), response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn fresh_start() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let (status, health) = get_health(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["auth"]["global"], false);
+    assert_eq!(health["auth"]["expires_at"], Value::Null);
+    assert_eq!(health["ingest"]["last_success"], Value::Null);
+    assert_eq!(health["last_error"], Value::Null);
+    assert_eq!(health["database"]["listens"], 0);
+    assert!(health["database"]["size_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(health["spotify"]["reachable"], true);
+}
+
+#[tokio::test]
+async fn after_ingesting() {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    let app = TestApp::start(&spotify).await;
+
+    app.authorize().await;
+    app.get("").await;
+
+    let (status, health) = get_health(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["auth"]["global"], true);
+    let expires_in = health["auth"]["expires_in_secs"].as_i64().unwrap();
+    assert!(3500 < expires_in && expires_in <= 3600, "{}", expires_in);
+    assert!(health["ingest"]["last_success"].is_string());
+    assert_eq!(health["database"]["listens"], 1);
+
+    spotify.expire_token();
+    app.get("").await;
+
+    let (_, health) = get_health(&app).await;
+    assert_eq!(health["last_error"]["context"], "recently-played");
+    // anyone can see this, so not what spotify said
+    assert_eq!(health["last_error"]["details"], Value::Null);
+    assert_eq!(health["last_error"]["message"], Value::Null);
+}
+
+#[tokio::test]
+async fn pings_spotify_now_and_then() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    for _ in 0..5 {
+        let (_, health) = get_health(&app).await;
+        assert_eq!(health["spotify"]["reachable"], true);
+    }
+    assert_eq!(*spotify.state.pings.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn stale() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |config| {
+        config.replace(
+            "get_new_limit = 2",
+            "get_new_limit = 2\nstale_after_secs = 0",
+        )
+    })
+    .await;
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    let (status, health) = get_health(&app).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(health["status"], "stale");
+}
+
+#[tokio::test]
+async fn spotify_unreachable() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |config| {
+        config.replace(spotify.url.as_str(), "http://127.0.0.1:1/")
+    })
+    .await;
+
+    let (status, health) = get_health(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["spotify"]["reachable"], false);
+    assert!(health["spotify"]["error"].is_string());
+}
@@ -0,0 +1,209 @@
+mod common;
+
+use common::{FakeLastFm, FakeSpotify, TestApp, LASTFM_SESSION_KEY};
+use spotti::{lastfm::LastFmClient, SongRecord};
+
+fn play_three(spotify: &FakeSpotify) {
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+    );
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead", "Thom Yorke"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+}
+
+async fn outbox(app: &TestApp) -> Vec<(String, i64, Option<String>)> {
+    sqlx::query_as(
+        "select date, attempts, submitted_at from outbox where service = 'lastfm' order by date",
+    )
+    .fetch_all(&app.state.pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn scrobbles_new_listens() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    let lastfm = FakeLastFm::start().await;
+    let config = lastfm.config_with_session();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    common::eventually(|| lastfm.scrobbles().len() == 3).await;
+
+    let scrobble = &lastfm.scrobbles()[2];
+    assert_eq!(scrobble["track"], "Nude");
+    assert_eq!(scrobble["artist"], "Radiohead, Thom Yorke");
+    assert_eq!(scrobble["album"], "In Rainbows");
+    assert_eq!(scrobble["timestamp"], "1704067800");
+
+    // nothing new, nothing sent again
+    app.get("").await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(lastfm.scrobbles().len(), 3);
+    assert!(outbox(&app)
+        .await
+        .iter()
+        .all(|(_, attempts, submitted_at)| *attempts == 0 && submitted_at.is_some()));
+}
+
+#[tokio::test]
+async fn fifty_at_a_time() {
+    let spotify = FakeSpotify::start().await;
+    let lastfm = FakeLastFm::start().await;
+    let config = lastfm.config_with_session();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    for i in 0..120 {
+        let date = format!("2024-01-01T{:02}:{:02}:00.000Z", i / 60, i % 60);
+        sqlx::query("insert into songs values ('Xtal', 'SAW 85-92', 'Aphex Twin', $1, $2)")
+            .bind(&date)
+            .bind(format!("track-{i}"))
+            .execute(&app.state.pool)
+            .await
+            .unwrap();
+        sqlx::query("insert into outbox (service, date) values ('lastfm', $1)")
+            .bind(&date)
+            .execute(&app.state.pool)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(spotti::outbox::forward(&app.state).await.unwrap(), 120);
+    let sizes = lastfm
+        .batches
+        .lock()
+        .unwrap()
+        .iter()
+        .map(Vec::len)
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, [50, 50, 20]);
+    // oldest first
+    assert_eq!(lastfm.scrobbles()[0]["timestamp"], "1704067200");
+}
+
+#[tokio::test]
+async fn retries_after_an_outage() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    let lastfm = FakeLastFm::start().await;
+    *lastfm.down.lock().unwrap() = true;
+    let config = lastfm.config_with_session();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    common::eventually(|| app.state.metrics.forwarded.get(&["lastfm", "error"]) == 3).await;
+    // and it's done writing that down
+    drop(app.state.forwarding.lock().await);
+    assert!(outbox(&app)
+        .await
+        .iter()
+        .all(|(_, attempts, submitted_at)| *attempts == 1 && submitted_at.is_none()));
+
+    let error: String = sqlx::query_scalar("select last_error from outbox limit 1")
+        .fetch_one(&app.state.pool)
+        .await
+        .unwrap();
+    assert!(error.contains("last.fm error 16"), "{}", error);
+
+    *lastfm.down.lock().unwrap() = false;
+    sqlx::query("update outbox set retry_at = '2000-01-01T00:00:00.000Z'")
+        .execute(&app.state.pool)
+        .await
+        .unwrap();
+    assert_eq!(spotti::outbox::forward(&app.state).await.unwrap(), 3);
+    assert_eq!(lastfm.scrobbles().len(), 3);
+}
+
+#[tokio::test]
+async fn nothing_without_a_session_key() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    let lastfm = FakeLastFm::start().await;
+    let config = lastfm.config();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    assert!(outbox(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn counts_ignored_scrobbles() {
+    let spotify = FakeSpotify::start().await;
+    let lastfm = FakeLastFm::start().await;
+    let config = lastfm.config_with_session();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    let listen = |artist: &str, date: &str| SongRecord {
+        name: Some(String::from("Untitled")),
+        album: None,
+        artist: Some(artist.to_owned()),
+        date: Some(date.to_owned()),
+        id: None,
+    };
+    let client = LastFmClient::new(
+        app.state.http.clone(),
+        app.state.config.lastfm.as_ref().unwrap(),
+    );
+    let ignored = client
+        .scrobble(
+            LASTFM_SESSION_KEY,
+            &[
+                listen("Aphex Twin", "2024-01-01T00:00:00.000Z"),
+                listen("", "2024-01-01T00:06:00.000Z"),
+            ],
+        )
+        .await
+        .unwrap();
+    assert_eq!(ignored, 1);
+}
+
+#[tokio::test]
+async fn session_key() {
+    let spotify = FakeSpotify::start().await;
+    let lastfm = FakeLastFm::start().await;
+    let config = lastfm.config();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    let session_key = spotti::cli::lastfm_auth(&app.state, &b"\n"[..])
+        .await
+        .unwrap();
+    assert_eq!(session_key, LASTFM_SESSION_KEY);
+}
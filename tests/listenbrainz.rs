@@ -0,0 +1,209 @@
+mod common;
+
+use common::{FakeListenBrainz, FakeSpotify, TestApp};
+
+fn play_three(spotify: &FakeSpotify) {
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+    );
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead", "Thom Yorke"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+}
+
+async fn outbox(app: &TestApp) -> Vec<(String, i64, Option<String>)> {
+    sqlx::query_as(
+        "select date, attempts, submitted_at from outbox where service = 'listenbrainz' order by date",
+    )
+    .fetch_all(&app.state.pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn forwards_new_listens() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    let listenbrainz = FakeListenBrainz::start().await;
+    let config = listenbrainz.config();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    common::eventually(|| listenbrainz.listens.lock().unwrap().len() == 3).await;
+    assert_eq!(listenbrainz.track_names(), ["Windowlicker", "Xtal", "Nude"]);
+
+    let listen = listenbrainz.listens.lock().unwrap()[2].clone();
+    assert_eq!(listen["listened_at"], 1704067800);
+    assert_eq!(
+        listen["track_metadata"]["artist_name"],
+        "Radiohead, Thom Yorke"
+    );
+    assert_eq!(
+        listen["track_metadata"]["additional_info"]["spotify_id"],
+        "https://open.spotify.com/track/track-3"
+    );
+
+    // nothing new, nothing sent again
+    app.get("").await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(listenbrainz.listens.lock().unwrap().len(), 3);
+    assert!(outbox(&app)
+        .await
+        .iter()
+        .all(|(_, attempts, submitted_at)| *attempts == 0 && submitted_at.is_some()));
+}
+
+#[tokio::test]
+async fn retries_after_an_outage() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    let listenbrainz = FakeListenBrainz::start().await;
+    *listenbrainz.down.lock().unwrap() = true;
+    let config = listenbrainz.config();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    common::eventually(|| app.state.metrics.forwarded.get(&["listenbrainz", "error"]) == 3).await;
+    // and it's done writing that down
+    drop(app.state.forwarding.lock().await);
+    assert!(outbox(&app)
+        .await
+        .iter()
+        .all(|(_, attempts, submitted_at)| *attempts == 1 && submitted_at.is_none()));
+
+    let error: String = sqlx::query_scalar("select last_error from outbox limit 1")
+        .fetch_one(&app.state.pool)
+        .await
+        .unwrap();
+    assert!(error.contains("503"), "{}", error);
+    // and it's on the errors page
+    let context: String = sqlx::query_scalar("select context from errors")
+        .fetch_one(&app.state.pool)
+        .await
+        .unwrap();
+    assert_eq!(context, "listenbrainz");
+
+    // not due yet
+    *listenbrainz.down.lock().unwrap() = false;
+    assert_eq!(spotti::outbox::forward(&app.state).await.unwrap(), 0);
+
+    sqlx::query("update outbox set retry_at = '2000-01-01T00:00:00.000Z'")
+        .execute(&app.state.pool)
+        .await
+        .unwrap();
+    assert_eq!(spotti::outbox::forward(&app.state).await.unwrap(), 3);
+    assert_eq!(listenbrainz.listens.lock().unwrap().len(), 3);
+    assert!(outbox(&app)
+        .await
+        .iter()
+        .all(|(_, attempts, submitted_at)| *attempts == 1 && submitted_at.is_some()));
+}
+
+#[tokio::test]
+async fn skips_listens_without_an_artist() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    spotify.play("Untitled", "", &[], "2024-01-01T00:20:00.000Z", "track-4");
+    let listenbrainz = FakeListenBrainz::start().await;
+    let config = listenbrainz.config();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    common::eventually(|| app.state.metrics.forwarded.get(&["listenbrainz", "ok"]) == 4).await;
+    assert_eq!(listenbrainz.track_names(), ["Windowlicker", "Xtal", "Nude"]);
+}
+
+#[tokio::test]
+async fn gives_up_on_rejected_listens() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    spotify.play(
+        "Prelude",
+        "Old",
+        &["Someone"],
+        "2001-01-01T00:00:00.000Z",
+        "track-0",
+    );
+    let listenbrainz = FakeListenBrainz::start().await;
+    let config = listenbrainz.config();
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |base| {
+        base + &config
+    })
+    .await;
+
+    app.authorize().await;
+    app.get("").await;
+    common::eventually(|| {
+        app.state
+            .metrics
+            .forwarded
+            .get(&["listenbrainz", "rejected"])
+            == 1
+            && app.state.metrics.forwarded.get(&["listenbrainz", "ok"]) == 3
+    })
+    .await;
+    // the rest still went, once it was found
+    assert_eq!(listenbrainz.track_names(), ["Windowlicker", "Xtal", "Nude"]);
+    drop(app.state.forwarding.lock().await);
+
+    let (error, rejected_at): (String, Option<String>) = sqlx::query_as(
+        "select last_error, rejected_at from outbox where date = '2001-01-01T00:00:00.000Z'",
+    )
+    .fetch_one(&app.state.pool)
+    .await
+    .unwrap();
+    assert!(error.contains("400"), "{}", error);
+    assert!(rejected_at.is_some());
+
+    // and it's not tried again
+    sqlx::query("update outbox set retry_at = '2000-01-01T00:00:00.000Z'")
+        .execute(&app.state.pool)
+        .await
+        .unwrap();
+    assert_eq!(spotti::outbox::forward(&app.state).await.unwrap(), 0);
+    assert_eq!(
+        app.state
+            .metrics
+            .forwarded
+            .get(&["listenbrainz", "rejected"]),
+        1
+    );
+}
+
+#[tokio::test]
+async fn off_by_default() {
+    let spotify = FakeSpotify::start().await;
+    play_three(&spotify);
+    let app = TestApp::start(&spotify).await;
+
+    app.authorize().await;
+    app.get("").await;
+    assert!(outbox(&app).await.is_empty());
+}
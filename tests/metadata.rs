@@ -0,0 +1,92 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+
+#[tokio::test]
+async fn everything_recently_played_says() {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+
+    let track: (i64, bool, i64, i64, i64, String) = sqlx::query_as(
+        "select duration_ms, explicit, popularity, track_number, disc_number, isrc
+        from tracks where id = 'track-3'",
+    )
+    .fetch_one(&app.state.pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        track,
+        (240_000, false, 50, 1, 1, String::from("ISRC-track-3"))
+    );
+
+    let (release_date, precision, images): (String, String, String) = sqlx::query_as(
+        "select release_date, release_date_precision, images from albums where id = 'album-In Rainbows'",
+    )
+    .fetch_one(&app.state.pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        (release_date.as_str(), precision.as_str()),
+        ("1999-03-22", "day")
+    );
+    let images: serde_json::Value = serde_json::from_str(&images).unwrap();
+    assert_eq!(images[0]["url"], "https://i.scdn.co/image/In Rainbows-640");
+    assert_eq!(images[1]["width"], 64);
+
+    let context: (String, String, String) = sqlx::query_as(
+        "select type, uri, href from listen_contexts where date = '2024-01-01T00:10:00.000Z'",
+    )
+    .fetch_one(&app.state.pool)
+    .await
+    .unwrap();
+    assert_eq!(context.0, "playlist");
+    assert_eq!(context.1, "spotify:playlist:fake-playlist");
+}
+
+#[tokio::test]
+async fn local_files_have_less() {
+    let spotify = FakeSpotify::start().await;
+    let mut listen = common::listen(
+        "demo",
+        "demos",
+        &["me"],
+        "2024-01-01T00:00:00.000Z",
+        "local-track",
+    );
+    let track = listen["track"].as_object_mut().unwrap();
+    for key in ["popularity", "external_ids", "explicit"] {
+        track.remove(key);
+    }
+    let album = track["album"].as_object_mut().unwrap();
+    album["release_date"] = serde_json::Value::Null;
+    album.remove("images");
+    listen["context"] = serde_json::Value::Null;
+    spotify.play_listen(listen);
+
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+    assert_eq!(app.count().await, 1);
+
+    let (popularity, isrc, duration_ms): (Option<i64>, Option<String>, Option<i64>) =
+        sqlx::query_as("select popularity, isrc, duration_ms from tracks")
+            .fetch_one(&app.state.pool)
+            .await
+            .unwrap();
+    assert_eq!((popularity, isrc, duration_ms), (None, None, Some(240_000)));
+
+    let contexts: i64 = sqlx::query_scalar("select count(*) from listen_contexts")
+        .fetch_one(&app.state.pool)
+        .await
+        .unwrap();
+    assert_eq!(contexts, 0);
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+
+/// the value of one sample, by its name and labels as they appear in the page
+fn value(metrics: &str, series: &str) -> Option<f64> {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .map(|value| value.parse().unwrap())
+}
+
+async fn metrics(app: &TestApp) -> String {
+    let response = app.get("metrics").await;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn fresh_start() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let metrics = metrics(&app).await;
+    assert!(metrics.contains("# TYPE spotti_ingest_runs_total counter"));
+    assert!(metrics.contains("# TYPE spotti_listens gauge"));
+    assert_eq!(value(&metrics, "spotti_rows_inserted_total"), Some(0.0));
+    assert_eq!(value(&metrics, "spotti_listens"), Some(0.0));
+    assert!(value(&metrics, "spotti_seconds_since_last_poll").is_some());
+}
+
+#[tokio::test]
+async fn counts() {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Flim",
+        "Come To Daddy",
+        &["Aphex Twin"],
+        "2024-01-01T00:10:00.000Z",
+        "track-2",
+    );
+    let app = TestApp::start(&spotify).await;
+
+    app.authorize().await;
+    app.get("").await;
+    app.get("").await;
+    app.get("refresh").await;
+    app.get("nothing/here").await;
+
+    let metrics = metrics(&app).await;
+    assert_eq!(
+        value(&metrics, r#"spotti_ingest_runs_total{result="ok"}"#),
+        Some(2.0)
+    );
+    assert_eq!(value(&metrics, "spotti_rows_inserted_total"), Some(2.0));
+    assert_eq!(value(&metrics, "spotti_listens"), Some(2.0));
+    assert_eq!(
+        value(
+            &metrics,
+            r#"spotti_spotify_requests_total{endpoint="recently-played",status="200"}"#
+        ),
+        Some(2.0)
+    );
+    assert_eq!(
+        value(
+            &metrics,
+            r#"spotti_spotify_requests_total{endpoint="token",status="200"}"#
+        ),
+        Some(2.0)
+    );
+    assert_eq!(
+        value(&metrics, r#"spotti_token_refreshes_total{result="ok"}"#),
+        Some(1.0)
+    );
+    assert_eq!(
+        value(
+            &metrics,
+            r#"spotti_http_requests_total{route="/",status="200"}"#
+        ),
+        Some(2.0)
+    );
+    assert_eq!(
+        value(
+            &metrics,
+            r#"spotti_http_requests_total{route="unmatched",status="404"}"#
+        ),
+        Some(1.0)
+    );
+    assert!(value(&metrics, "spotti_seconds_since_last_poll").unwrap() < 5.0);
+}
+
+#[tokio::test]
+async fn failures() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    app.authorize().await;
+    spotify.expire_token();
+    app.get("").await;
+
+    let metrics = metrics(&app).await;
+    assert_eq!(
+        value(&metrics, r#"spotti_ingest_runs_total{result="error"}"#),
+        Some(1.0)
+    );
+    assert_eq!(
+        value(
+            &metrics,
+            r#"spotti_spotify_requests_total{endpoint="recently-played",status="401"}"#
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        value(
+            &metrics,
+            r#"spotti_http_requests_total{route="/",status="401"}"#
+        ),
+        Some(1.0)
+    );
+}
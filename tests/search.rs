@@ -0,0 +1,123 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use serde_json::Value;
+
+async fn app_with_listens() -> (FakeSpotify, TestApp) {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+    );
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+    spotify.play(
+        "Hoppípolla",
+        "Takk...",
+        &["Sigur Rós"],
+        "2024-01-01T00:15:00.000Z",
+        "track-4",
+    );
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-02T00:00:00.000Z",
+        "track-1",
+    );
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+    (spotify, app)
+}
+
+async fn search(app: &TestApp, q: &str) -> Value {
+    let response = app
+        .client
+        .get(app.url.join("search").unwrap())
+        .query(&[("q", q)])
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+fn names(results: &Value) -> Vec<&str> {
+    results["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["name"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn by_name_album_or_artist() {
+    let (_spotify, app) = app_with_listens().await;
+
+    // most recently played first
+    let results = search(&app, "aphex").await;
+    assert_eq!(names(&results), ["Windowlicker", "Xtal"]);
+    assert_eq!(results["results"][0]["plays"], 2);
+    assert_eq!(
+        results["results"][0]["played_at"],
+        serde_json::json!(["2024-01-02T00:00:00.000Z", "2024-01-01T00:00:00.000Z"])
+    );
+
+    assert_eq!(names(&search(&app, "ambient works").await), ["Xtal"]);
+    assert_eq!(names(&search(&app, "nude").await), ["Nude"]);
+    // prefixes, and without the accents
+    assert_eq!(names(&search(&app, "rainb").await), ["Nude"]);
+    assert_eq!(names(&search(&app, "sigur ros").await), ["Hoppípolla"]);
+
+    assert!(names(&search(&app, "aphex rainbows").await).is_empty());
+}
+
+#[tokio::test]
+async fn nothing_special_about_the_query() {
+    let (_spotify, app) = app_with_listens().await;
+
+    for q in ["", "   ", "\"", "aphex OR", "NEAR(", "name:*", "-", "^"] {
+        assert!(names(&search(&app, q).await).is_empty(), "{}", q);
+    }
+    assert_eq!(names(&search(&app, "\"nude\"").await), ["Nude"]);
+}
+
+#[tokio::test]
+async fn search_page() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let page = app.get("").await.text().await.unwrap();
+    assert!(page.contains("<form action=/search>"));
+
+    let page = app.get("search?q=windowlicker").await.text().await.unwrap();
+    assert!(page.contains("<td>Aphex Twin</td>"));
+    assert!(page.contains("1 more"));
+    assert!(page.contains("value=\"windowlicker\""));
+
+    let page = app
+        .get("search?q=%22%3E%3Cscript%3E")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(!page.contains("\"><script>"));
+    assert!(page.contains("never, apparently"));
+}
@@ -0,0 +1,403 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn get_new_without_auth() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.insert(
+        "Windowlicker",
+        "Windowlicker",
+        "Aphex Twin",
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    )
+    .await;
+
+    let response = app.get("").await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        reqwest::header::HeaderValue::from_static("*")
+    );
+
+    let page = response.text().await.unwrap();
+    assert!(page.contains("global auth was not available"));
+    assert!(page.contains("Windowlicker"));
+    assert!(page.contains("log in"));
+}
+
+#[tokio::test]
+async fn authorize_redirects_to_spotify() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let response = app.get("authorize").await;
+    assert!(response.status().is_redirection());
+
+    let location = response.headers()["location"].to_str().unwrap();
+    assert!(location.starts_with(spotify.url.join("authorize").unwrap().as_str()));
+    assert!(location.contains(common::CLIENT_ID));
+    assert!(location.contains("spotti.test"));
+}
+
+#[tokio::test]
+async fn authorize_and_ingest() {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+    );
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead", "Thom Yorke"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+    let app = TestApp::start(&spotify).await;
+
+    let response = app.authorize().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("and very handsome at that"));
+
+    let response = app.get("").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = response.text().await.unwrap();
+    assert!(!page.contains("global auth was not available"));
+    assert!(page.contains(
+        ">Radiohead</a>, <a href=http://spotti.test/artist/artist-Thom%20Yorke>Thom Yorke</a>"
+    ));
+    assert!(page.contains("Xtal"));
+    assert!(!page.contains("Windowlicker"), "get_new_limit is 2");
+    assert!(page.contains("spotify:track:track-3"));
+    assert_eq!(app.count().await, 3);
+
+    let page = app.get("all").await.text().await.unwrap();
+    assert!(page.contains("Windowlicker"));
+
+    app.get("").await;
+    assert_eq!(app.count().await, 3, "listens are only stored once");
+}
+
+#[tokio::test]
+async fn authorize_someone_else() {
+    let spotify = FakeSpotify::start().await;
+    spotify.set_user("someone else");
+    let app = TestApp::start(&spotify).await;
+
+    let page = app.authorize().await.text().await.unwrap();
+    assert!(page.contains("not globally though"));
+
+    let response = app.get("").await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let page = response.text().await.unwrap();
+    assert!(!page.contains("log in"), "they have a session though");
+}
+
+#[tokio::test]
+async fn refresh() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let response = app.get("refresh").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.text().await.unwrap().contains("unauthorized"));
+    assert!(
+        !app.dir.path().join("error").exists(),
+        "not worth reporting"
+    );
+
+    app.authorize().await;
+    spotify.expire_token();
+
+    let response = app.get("").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("recently-played"));
+    assert!(page.contains("invalid token"));
+    let error_file = app.dir.path().join("error");
+    common::eventually(|| error_file.exists()).await;
+    assert!(std::fs::read_to_string(error_file).unwrap().contains("401"));
+
+    let response = app.get("refresh").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("refreshing"));
+
+    let response = app.get("").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn revoked_refresh_token_asks_to_log_in_again() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    let tokens = spotti::TokenPair {
+        access_token: spotify.access_token(),
+        refresh_token: String::from("revoked"),
+        expires_in: None,
+    };
+    spotti::server::set_global_auth(&app.state, tokens)
+        .await
+        .unwrap();
+
+    let response = app.get("refresh").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.text().await.unwrap().contains("invalid_grant"));
+}
+
+#[tokio::test]
+async fn uptime() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let response = app.get("uptime").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let uptime = response.text().await.unwrap();
+    let parts = uptime.split(' ').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 4, "{}", uptime);
+    for (part, unit) in parts.iter().zip(["d", "h", "m", "s"]) {
+        assert!(part.ends_with(unit), "{}", uptime);
+        part.trim_end_matches(unit).parse::<u64>().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn path_prefix() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |config| {
+        format!("path_prefix = \"spotti\"\n{config}").replace(
+            "uptime_endpoint = \"uptime\"",
+            "uptime_endpoint = \"stats/uptime\"",
+        )
+    })
+    .await;
+
+    assert_eq!(
+        app.get("spotti/stats/uptime").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(app.get("uptime").await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        app.get("spotti/stats%2Fuptime").await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    // links on the page go where the routes are
+    let page = app.get("spotti/").await.text().await.unwrap();
+    assert!(
+        page.contains("<a href=http://spotti.test/spotti/all>"),
+        "{}",
+        page
+    );
+    assert!(
+        page.contains("<a href=http://spotti.test/spotti/authorize>"),
+        "{}",
+        page
+    );
+
+    let response = app.get("spotti/authorize").await;
+    let location = response.headers()["location"].to_str().unwrap();
+    assert!(
+        location.contains("redirect_uri=http%3A%2F%2Fspotti.test%2Fspotti%2Fauthorize"),
+        "{}",
+        location
+    );
+}
+
+#[tokio::test]
+async fn not_found() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let response = app.get("what/is/this").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.text().await.unwrap().contains("/what/is/this"));
+}
+
+#[tokio::test]
+async fn apps_are_independent() {
+    let spotify = FakeSpotify::start().await;
+    let first = TestApp::start(&spotify).await;
+    let second = TestApp::start(&spotify).await;
+
+    first.authorize().await;
+    assert_eq!(first.get("").await.status(), StatusCode::OK);
+    assert_eq!(second.get("").await.status(), StatusCode::ACCEPTED);
+    assert!(second.state.global_auth.read().unwrap().is_none());
+}
+
+#[tokio::test]
+async fn database_uses_wal() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let mode: String = sqlx::query_scalar("pragma journal_mode")
+        .fetch_one(&app.state.pool)
+        .await
+        .unwrap();
+    assert_eq!(mode, "wal");
+}
+
+#[tokio::test]
+async fn errors_as_json() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    spotify.expire_token();
+
+    let response = app
+        .client
+        .get(app.url.clone())
+        .header("accept", "application/json, text/html;q=0.9")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(error["error"], "auth");
+    assert_eq!(error["status"], 401);
+    assert_eq!(error["context"], "recently-played");
+    assert!(error["details"].as_str().unwrap().contains("invalid token"));
+}
+
+#[tokio::test]
+async fn spotify_down() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start_with(&spotify, tempfile::tempdir().unwrap(), |config| {
+        config.replace(spotify.url.as_str(), "http://127.0.0.1:1/")
+    })
+    .await;
+
+    let response = app.get("authorize?code=whatever").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert!(response.text().await.unwrap().contains("502 bad gateway"));
+}
+
+#[tokio::test]
+async fn error_history() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let response = app.get("errors").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    app.authorize().await;
+    let page = app.get("errors").await.text().await.unwrap();
+    assert!(page.contains("nothing! nice"));
+
+    spotify.expire_token();
+    app.get("").await;
+    app.get("all").await;
+
+    let page = app.get("errors").await.text().await.unwrap();
+    assert!(page.contains("recently-played"));
+    assert!(page.contains("spotify returned 401"));
+    assert!(page.contains("invalid token"));
+
+    let history: serde_json::Value = app
+        .client
+        .get(app.url.join("errors").unwrap())
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(history["counts"].as_array().unwrap().len(), 1);
+    assert_eq!(history["counts"][0]["count"], 2);
+    assert_eq!(history["counts"][0]["context"], "recently-played");
+    assert_eq!(history["recent"].as_array().unwrap().len(), 2);
+    assert!(history["recent"][0]["details"]
+        .as_str()
+        .unwrap()
+        .contains("invalid token"));
+}
+
+#[tokio::test]
+async fn error_history_is_owner_only() {
+    let spotify = FakeSpotify::start().await;
+    spotify.set_user("someone else");
+    let app = TestApp::start(&spotify).await;
+
+    app.authorize().await;
+    let response = app.get("errors").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn reload() {
+    let spotify = FakeSpotify::start().await;
+    for (i, name) in ["Xtal", "Tha", "Pulsewidth"].iter().enumerate() {
+        spotify.play(
+            name,
+            "Selected Ambient Works 85-92",
+            &["Aphex Twin"],
+            &format!("2024-01-01T00:0{i}:00.000Z"),
+            &format!("track-{i}"),
+        );
+    }
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    assert!(!app.get("").await.text().await.unwrap().contains("Xtal"));
+
+    let changes = app
+        .reload(|config| {
+            config
+                .replace("get_new_limit = 2", "get_new_limit = 3")
+                .replace(
+                    "show_all_endpoint = \"all\"",
+                    "show_all_endpoint = \"everything\"",
+                )
+        })
+        .unwrap();
+    assert_eq!(changes, ["show_all_url", "get_new_limit"]);
+
+    assert!(app.get("").await.text().await.unwrap().contains("Xtal"));
+    assert_eq!(app.get("all").await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(app.get("everything").await.status(), StatusCode::OK);
+    assert_eq!(
+        app.get("errors").await.status(),
+        StatusCode::OK,
+        "still logged in"
+    );
+}
+
+#[tokio::test]
+async fn reload_immutable() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let err = app
+        .reload(|config| {
+            config
+                .replace("127.0.0.1:0", "127.0.0.1:1")
+                .replace("get_new_limit = 2", "get_new_limit = 3")
+        })
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad config: `address` can't change without a restart"
+    );
+    assert_eq!(app.reloadable.state().config.get_new_limit, 2);
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use common::FakeSpotify;
+use spotti::SpotifyError;
+use url::Url;
+
+fn redirect() -> Url {
+    Url::parse("https://spotti.example/authorize").unwrap()
+}
+
+#[tokio::test]
+async fn authorize_url_points_at_accounts() {
+    let spotify = FakeSpotify::start().await;
+    let url = spotify.client().authorize_url(&redirect()).unwrap();
+
+    assert_eq!(url.path(), "/authorize");
+    assert!(url.as_str().starts_with(spotify.url.as_str()));
+
+    let query = url.query_pairs().into_owned().collect::<Vec<_>>();
+    assert!(query.contains(&("response_type".into(), "code".into())));
+    assert!(query.contains(&("client_id".into(), common::CLIENT_ID.into())));
+    assert!(query.contains(&("redirect_uri".into(), redirect().to_string())));
+}
+
+#[tokio::test]
+async fn auth_flow() {
+    let spotify = FakeSpotify::start().await;
+    let client = spotify.client();
+
+    let tokens = client
+        .request_token(common::CODE, &redirect())
+        .await
+        .unwrap();
+    assert_eq!(tokens.access_token, spotify.access_token());
+    assert_eq!(tokens.refresh_token, common::REFRESH_TOKEN);
+
+    let me = client.me(&tokens.access_token).await.unwrap();
+    assert_eq!(me.id, spotti::ME);
+
+    let requests = spotify.state.token_requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["redirect_uri"], redirect().as_str());
+}
+
+#[tokio::test]
+async fn bad_code_keeps_body() {
+    let spotify = FakeSpotify::start().await;
+
+    let err = spotify
+        .client()
+        .request_token("not the code", &redirect())
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err, SpotifyError::Status { status: 400, .. }));
+    assert!(err.body().unwrap().contains("invalid_grant"));
+    assert!(err.is_unauthorized());
+}
+
+#[test]
+fn only_invalid_grant_is_unauthorized() {
+    let err = SpotifyError::Status {
+        status: 400,
+        body: String::from(r#"{"error":"invalid_client"}"#),
+    };
+    assert!(!err.is_unauthorized());
+    let err = SpotifyError::Status {
+        status: 400,
+        body: String::from("invalid_grant"),
+    };
+    assert!(!err.is_unauthorized());
+}
+
+#[tokio::test]
+async fn refresh_flow() {
+    let spotify = FakeSpotify::start().await;
+    let client = spotify.client();
+
+    let tokens = client
+        .request_token(common::CODE, &redirect())
+        .await
+        .unwrap();
+    spotify.expire_token();
+    assert!(client
+        .recently_played(&tokens.access_token, 50)
+        .await
+        .unwrap_err()
+        .is_unauthorized());
+
+    let refreshed = client
+        .refresh_token(&tokens.refresh_token, &redirect())
+        .await
+        .unwrap();
+    assert_ne!(refreshed.access_token, tokens.access_token);
+    assert_eq!(refreshed.refresh_token, None);
+
+    client
+        .recently_played(&refreshed.access_token, 50)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn recently_played() {
+    let spotify = FakeSpotify::start().await;
+    spotify.play(
+        "Windowlicker",
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2024-01-01T00:00:00.000Z",
+        "track-1",
+    );
+    spotify.play(
+        "Xtal",
+        "Selected Ambient Works 85-92",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+    );
+    spotify.play(
+        "Nude",
+        "In Rainbows",
+        &["Radiohead", "Thom Yorke"],
+        "2024-01-01T00:10:00.000Z",
+        "track-3",
+    );
+
+    let client = spotify.client();
+    let tokens = client
+        .request_token(common::CODE, &redirect())
+        .await
+        .unwrap();
+
+    let listens = client
+        .recently_played(&tokens.access_token, 50)
+        .await
+        .unwrap();
+    assert_eq!(listens.items.len(), 3);
+    assert_eq!(listens.items[0].track.name, "Nude");
+    assert_eq!(listens.items[0].track.artists.len(), 2);
+    assert_eq!(listens.items[2].played_at, "2024-01-01T00:00:00.000Z");
+
+    let listens = client
+        .recently_played(&tokens.access_token, 2)
+        .await
+        .unwrap();
+    assert_eq!(listens.items.len(), 2);
+}
@@ -0,0 +1,196 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+fn play(
+    spotify: &FakeSpotify,
+    name: &str,
+    artists: &[&str],
+    played_at: &str,
+    id: &str,
+    ms: Option<i64>,
+) {
+    let mut listen = common::listen(name, name, artists, played_at, id);
+    let track = listen["track"].as_object_mut().unwrap();
+    match ms {
+        Some(ms) => track["duration_ms"] = ms.into(),
+        None => {
+            track.remove("duration_ms");
+        }
+    }
+    spotify.play_listen(listen);
+}
+
+async fn app_with_listens() -> (FakeSpotify, TestApp) {
+    let spotify = FakeSpotify::start().await;
+    play(
+        &spotify,
+        "Windowlicker",
+        &["Aphex Twin"],
+        "2023-12-31T23:00:00.000Z",
+        "track-1",
+        Some(240_000),
+    );
+    play(
+        &spotify,
+        "Xtal",
+        &["Aphex Twin"],
+        "2024-01-01T00:06:00.000Z",
+        "track-2",
+        Some(390_000),
+    );
+    play(
+        &spotify,
+        "Nude",
+        &["Radiohead", "Thom Yorke"],
+        "2024-01-01T00:20:00.000Z",
+        "track-3",
+        Some(180_000),
+    );
+    play(
+        &spotify,
+        "demo",
+        &["Radiohead"],
+        "2024-02-10T12:00:00.000Z",
+        "track-4",
+        None,
+    );
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+    (spotify, app)
+}
+
+async fn json(app: &TestApp, path: &str) -> Value {
+    let response = app
+        .client
+        .get(app.url.join(path).unwrap())
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+fn periods(times: &Value) -> Vec<(&str, i64)> {
+    times
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|time| {
+            (
+                time["period"].as_str().unwrap(),
+                time["ms"].as_i64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn totals() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let stats = json(&app, "stats").await;
+    assert_eq!(
+        stats["total"],
+        serde_json::json!({ "listens": 4, "ms": 810_000, "minutes": 13.5, "untimed": 1 })
+    );
+    assert_eq!(
+        periods(&stats["days"]),
+        [
+            ("2023-12-31", 240_000),
+            ("2024-01-01", 570_000),
+            ("2024-02-10", 0)
+        ]
+    );
+    assert_eq!(stats["days"][1]["minutes"], 9.5);
+    assert_eq!(stats["days"][2]["untimed"], 1);
+    // iso weeks, so new year's eve is still in the last week of 2023
+    assert_eq!(
+        periods(&stats["weeks"]),
+        [
+            ("2023-W52", 240_000),
+            ("2024-W01", 570_000),
+            ("2024-W06", 0)
+        ]
+    );
+    assert_eq!(
+        periods(&stats["months"]),
+        [("2023-12", 240_000), ("2024-01", 570_000), ("2024-02", 0)]
+    );
+    assert_eq!(
+        periods(&stats["years"]),
+        [("2023", 240_000), ("2024", 570_000)]
+    );
+
+    let artists = stats["artists"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|artist| {
+            (
+                artist["name"].as_str().unwrap(),
+                artist["listens"].as_i64().unwrap(),
+                artist["ms"].as_i64().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        artists,
+        [
+            ("Aphex Twin", 2, 630_000),
+            ("Radiohead", 2, 180_000),
+            ("Thom Yorke", 1, 180_000),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn range() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let stats = json(&app, "stats?from=2024-01-01&to=2024-01-31").await;
+    assert_eq!(stats["total"]["listens"], 2);
+    assert_eq!(stats["total"]["ms"], 570_000);
+    assert_eq!(periods(&stats["years"]), [("2024", 570_000)]);
+}
+
+#[tokio::test]
+async fn page() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let main = app.get("").await.text().await.unwrap();
+    assert!(main.contains("<a href=http://spotti.test/stats>"));
+
+    let page = app.get("stats").await.text().await.unwrap();
+    assert!(page.contains("0h 13m over 4 listens"));
+    assert!(page.contains("1 of them aren't counted"));
+    // a calendar for each year, newest first
+    let (y2024, y2023) = (
+        page.find("<h2>2024</h2>").unwrap(),
+        page.find("<h2>2023</h2>").unwrap(),
+    );
+    assert!(y2024 < y2023);
+    assert!(page.contains("<title>2024-01-01: 9 minutes</title>"));
+    assert!(page.contains("<title>2024-12-31: 0 minutes</title>"));
+    assert!(page.contains(
+        "<td>2024</td><td>0h 9m</td><td>3</td><td><a href=http://spotti.test/wrapped?from=2024-01-01&to=2024-12-31>wrapped</a></td>"
+    ));
+    assert!(page.contains("<a href=http://spotti.test/artist/artist-Aphex%20Twin>Aphex Twin</a>"));
+}
+
+#[tokio::test]
+async fn nothing_yet() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+
+    let stats = json(&app, "stats").await;
+    assert_eq!(stats["total"]["listens"], 0);
+    assert_eq!(stats["days"], serde_json::json!([]));
+    let page = app.get("stats").await;
+    assert_eq!(page.status(), StatusCode::OK);
+    assert!(!page.text().await.unwrap().contains("<svg"));
+}
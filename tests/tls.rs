@@ -0,0 +1,121 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use spotti::{
+    tls::{self, Tls},
+    TlsConfig,
+};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+use url::Url;
+
+fn cert(n: u32) -> Vec<u8> {
+    std::fs::read(Path::new("tests/certs").join(format!("cert{n}.pem"))).unwrap()
+}
+
+fn key(n: u32) -> Vec<u8> {
+    std::fs::read(Path::new("tests/certs").join(format!("key{n}.pem"))).unwrap()
+}
+
+/// a client that only trusts the `n`th test certificate
+fn client(n: u32) -> reqwest::Client {
+    reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&cert(n)).unwrap())
+        .build()
+        .unwrap()
+}
+
+/// like it was written a while ago, instead of waiting for it to settle
+fn settle(path: &Path) {
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(an_hour_ago)
+        .unwrap();
+}
+
+/// the app again, over https, with the certificate and key somewhere we can
+/// swap them out
+async fn serve_tls(app: &TestApp) -> (Url, TlsConfig) {
+    let config = TlsConfig {
+        cert: app.dir.path().join("cert.pem"),
+        key: app.dir.path().join("key.pem"),
+        redirect_address: None,
+    };
+    std::fs::write(&config.cert, cert(1)).unwrap();
+    std::fs::write(&config.key, key(1)).unwrap();
+
+    let tls = Arc::new(Tls::new(config.clone()).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!(
+        "https://localhost:{}/",
+        listener.local_addr().unwrap().port()
+    ))
+    .unwrap();
+    tokio::spawn(tls::serve(
+        listener,
+        tls,
+        app.reloadable.router(),
+        std::future::pending(),
+    ));
+    (url, config)
+}
+
+#[tokio::test]
+async fn https() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    let (url, _) = serve_tls(&app).await;
+
+    let response = client(1).get(url.join("uptime").unwrap()).send().await;
+    assert_eq!(response.unwrap().status(), 200);
+
+    assert!(client(2).get(url).send().await.is_err());
+}
+
+#[tokio::test]
+async fn renewed_certificate() {
+    let spotify = FakeSpotify::start().await;
+    let app = TestApp::start(&spotify).await;
+    let (url, config) = serve_tls(&app).await;
+
+    // half a renewal keeps the old one going
+    std::fs::write(&config.cert, cert(2)).unwrap();
+    let response = client(1).get(url.join("uptime").unwrap()).send().await;
+    assert_eq!(response.unwrap().status(), 200);
+
+    std::fs::write(&config.key, key(2)).unwrap();
+    settle(&config.cert);
+    settle(&config.key);
+    let response = client(2).get(url.join("uptime").unwrap()).send().await;
+    assert_eq!(response.unwrap().status(), 200);
+    assert!(client(1).get(url).send().await.is_err());
+}
+
+#[tokio::test]
+async fn redirect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/all?page=2", listener.local_addr().unwrap());
+    let app = tls::redirect(Url::parse("https://spotti.test/").unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(url)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 308);
+    assert_eq!(
+        response.headers()["location"],
+        "https://spotti.test/all?page=2"
+    );
+}
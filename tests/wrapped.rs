@@ -0,0 +1,217 @@
+mod common;
+
+use common::{FakeSpotify, TestApp};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+async fn app_with_listens() -> (FakeSpotify, TestApp) {
+    let spotify = FakeSpotify::start().await;
+    let plays = [
+        (
+            "Xtal",
+            "SAW 85-92",
+            "Aphex Twin",
+            "2023-06-01T10:00:00.000Z",
+            "track-2",
+        ),
+        (
+            "Xtal",
+            "SAW 85-92",
+            "Aphex Twin",
+            "2024-01-01T10:00:00.000Z",
+            "track-2",
+        ),
+        (
+            "Windowlicker",
+            "Windowlicker",
+            "Aphex Twin",
+            "2024-01-02T10:30:00.000Z",
+            "track-1",
+        ),
+        (
+            "Windowlicker",
+            "Windowlicker",
+            "Aphex Twin",
+            "2024-01-02T11:00:00.000Z",
+            "track-1",
+        ),
+        (
+            "Nude",
+            "In Rainbows",
+            "Radiohead",
+            "2024-01-03T22:00:00.000Z",
+            "track-3",
+        ),
+        (
+            "Xtal",
+            "SAW 85-92",
+            "Aphex Twin",
+            "2024-01-05T10:00:00.000Z",
+            "track-2",
+        ),
+        (
+            "Nude",
+            "In Rainbows",
+            "Radiohead",
+            "2025-01-01T10:00:00.000Z",
+            "track-3",
+        ),
+    ];
+    for (name, album, artist, played_at, id) in plays {
+        spotify.play(name, album, &[artist], played_at, id);
+    }
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+    (spotify, app)
+}
+
+async fn json(app: &TestApp, path: &str) -> Value {
+    let response = app
+        .client
+        .get(app.url.join(path).unwrap())
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+fn names<'a>(list: &'a Value, key: &str) -> Vec<&'a str> {
+    list.as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item[key].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn report() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let wrapped = json(&app, "wrapped?from=2024-01-01&to=2024-12-31").await;
+    assert_eq!(wrapped["from"], "2024-01-01T00:00:00.000Z");
+    assert_eq!(wrapped["to"], "2025-01-01T00:00:00.000Z");
+    assert_eq!(wrapped["total"]["listens"], 5);
+    assert_eq!(wrapped["total"]["minutes"], 20.0);
+    // a tie goes to the one played last
+    assert_eq!(
+        names(&wrapped["top_tracks"], "name"),
+        ["Xtal", "Windowlicker", "Nude"]
+    );
+    assert_eq!(
+        names(&wrapped["top_artists"], "name"),
+        ["Aphex Twin", "Radiohead"]
+    );
+    assert_eq!(wrapped["top_artists"][0]["listens"], 4);
+    assert_eq!(
+        names(&wrapped["top_albums"], "name"),
+        ["SAW 85-92", "Windowlicker", "In Rainbows"]
+    );
+    assert_eq!(wrapped["busiest_day"]["period"], "2024-01-02");
+    assert_eq!(wrapped["busiest_day"]["listens"], 2);
+    assert_eq!(
+        wrapped["longest_streak"],
+        json!({ "days": 3, "from": "2024-01-01", "to": "2024-01-03" })
+    );
+    // aphex twin was found in 2023
+    assert_eq!(names(&wrapped["new_artists"], "name"), ["Radiohead"]);
+    assert_eq!(
+        wrapped["new_artists"][0]["first"],
+        "2024-01-03T22:00:00.000Z"
+    );
+
+    let hours = wrapped["hours"].as_array().unwrap();
+    assert_eq!(hours.len(), 24);
+    assert_eq!(
+        (&hours[10], &hours[11], &hours[22]),
+        (&json!(3), &json!(1), &json!(1))
+    );
+    assert_eq!(hours.iter().filter_map(Value::as_i64).sum::<i64>(), 5);
+}
+
+#[tokio::test]
+async fn page() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let page = app
+        .get("wrapped?from=2024-01-01&to=2024-12-31")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("<h1>zack's wrapped: 2024-01-01 to 2024-12-31</h1>"));
+    assert!(page.contains("<span class=big>20</span> minutes over 5 listens"));
+    assert!(page.contains("<td>1</td><td>Xtal</td><td>Aphex Twin</td><td>2</td>"));
+    assert!(page.contains("<span class=big>3 days</span> in a row"));
+    assert!(page.contains("found for the first time: Radiohead"));
+    assert!(page.contains("<title>10:00: 3</title>"));
+    // nothing to load from anywhere else
+    for outside in ["<script", "<link", "src=", "href="] {
+        assert!(!page.contains(outside), "{}", outside);
+    }
+
+    let everything = app.get("wrapped").await.text().await.unwrap();
+    assert!(everything.contains("zack's wrapped: all time"));
+    assert!(everything.contains("over 7 listens"));
+}
+
+#[tokio::test]
+async fn nothing_in_range() {
+    let (_spotify, app) = app_with_listens().await;
+
+    let wrapped = json(&app, "wrapped?from=2020-01-01&to=2020-12-31").await;
+    assert_eq!(wrapped["total"]["listens"], 0);
+    assert_eq!(wrapped["busiest_day"], Value::Null);
+    assert_eq!(wrapped["longest_streak"], Value::Null);
+
+    let page = app
+        .get("wrapped?from=2020-01-01")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("zack's wrapped: since 2020-01-01"));
+
+    assert_eq!(
+        app.get("wrapped?from=yesterday").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn busiest_day_is_the_most_played() {
+    let spotify = FakeSpotify::start().await;
+    // lots of plays we don't know the length of
+    for minute in 0..5 {
+        let mut listen = common::listen(
+            "demo",
+            "demos",
+            &["me"],
+            &format!("2024-03-01T10:0{minute}:00.000Z"),
+            "local-track",
+        );
+        listen["track"]
+            .as_object_mut()
+            .unwrap()
+            .remove("duration_ms");
+        spotify.play_listen(listen);
+    }
+    // against one long one
+    spotify.play(
+        "Xtal",
+        "SAW 85-92",
+        &["Aphex Twin"],
+        "2024-03-02T10:00:00.000Z",
+        "track-2",
+    );
+    let app = TestApp::start(&spotify).await;
+    app.authorize().await;
+    app.get("").await;
+
+    let wrapped = json(&app, "wrapped").await;
+    assert_eq!(wrapped["busiest_day"]["period"], "2024-03-01");
+    assert_eq!(wrapped["busiest_day"]["listens"], 5);
+    assert_eq!(wrapped["busiest_day"]["ms"], 0);
+}